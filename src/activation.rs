@@ -0,0 +1,187 @@
+//! Listening sockets passed in by systemd socket activation, as described in sd_listen_fds(3).
+
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use tokio::net::{TcpListener, UdpSocket};
+
+// only constructed from the environment on Unix
+#[cfg_attr(not(unix), allow(dead_code))]
+enum Socket {
+    Tcp(std::net::TcpListener, SocketAddr),
+    Udp(std::net::UdpSocket, SocketAddr),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, PathBuf),
+}
+
+impl Socket {
+    fn try_clone(&self) -> Result<Self, io::Error> {
+        Ok(match self {
+            Socket::Tcp(listener, addr) => Socket::Tcp(listener.try_clone()?, *addr),
+            Socket::Udp(socket, addr) => Socket::Udp(socket.try_clone()?, *addr),
+            #[cfg(unix)]
+            Socket::Unix(listener, path) => Socket::Unix(listener.try_clone()?, path.clone()),
+        })
+    }
+}
+
+/// Inherited sockets, each used instead of binding the address it's already bound to.
+/// They're shared by all the servers in the process, so unused ones are only closed once every server has bound.
+///
+/// Servers get a duplicate of each socket they use, and the original is kept open, like systemd keeps its own,
+/// so that a server restarted by a reload can use it again instead of binding a port it may not be allowed to.
+#[derive(Default)]
+pub struct Inherited {
+    /// Each socket's name, the socket unless it was closed, and whether a server has used it.
+    sockets: Vec<(String, Option<Socket>, bool)>,
+    /// Servers which haven't called `bound` yet.
+    unbound: usize,
+}
+
+impl Inherited {
+    /// Takes ownership of the sockets listed in `LISTEN_FDS` and `LISTEN_FDNAMES`, if they're meant for this process,
+    /// to be used by the given number of `servers`.
+    #[cfg(unix)]
+    pub fn from_env(servers: usize) -> Result<Self, io::Error> {
+        use socket2::{Domain, Type};
+        use std::env;
+        use std::os::unix::io::FromRawFd;
+
+        // the first passed file descriptor, after stdin, stdout, and stderr
+        const LISTEN_FDS_START: i32 = 3;
+
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        let names = env::var("LISTEN_FDNAMES").ok();
+        // so that they aren't inherited again by any child process
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(var);
+        }
+        match pid {
+            Some(pid) if pid == std::process::id().to_string() => {}
+            _ => return Ok(Self::default()),
+        }
+        let fds = fds
+            .unwrap_or_default()
+            .parse::<i32>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid LISTEN_FDS"))?;
+        let mut names = names.as_deref().unwrap_or_default().split(':');
+
+        let mut sockets = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(fds) {
+            let name = match names.next() {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => format!("fd {}", fd),
+            };
+            // safety: systemd passes ownership of these file descriptors to us
+            let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            socket.set_nonblocking(true)?;
+            let (domain, ty) = (socket.domain()?, socket.r#type()?);
+            let socket = if domain == Domain::UNIX {
+                match ty == Type::STREAM && socket.is_listener()? {
+                    true => {
+                        let listener = std::os::unix::net::UnixListener::from(socket);
+                        let path = listener.local_addr()?.as_pathname().map(Path::to_path_buf);
+                        path.map(|path| Socket::Unix(listener, path))
+                    }
+                    false => None,
+                }
+            } else {
+                match socket.local_addr()?.as_socket() {
+                    Some(addr) if ty == Type::STREAM && socket.is_listener()? => {
+                        Some(Socket::Tcp(socket.into(), addr))
+                    }
+                    Some(addr) if ty == Type::DGRAM => Some(Socket::Udp(socket.into(), addr)),
+                    _ => None,
+                }
+            };
+            if socket.is_none() {
+                log::warn!(
+                    "Ignoring inherited socket {}: not a TCP, UDP, or Unix listener",
+                    name
+                );
+            }
+            sockets.push((name, socket, false));
+        }
+        let mut inherited = Self {
+            sockets,
+            unbound: servers,
+        };
+        if servers == 0 {
+            inherited.close_unused();
+        }
+        Ok(inherited)
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_env(_servers: usize) -> Result<Self, io::Error> {
+        Ok(Self::default())
+    }
+
+    fn duplicate(
+        &mut self,
+        matches: impl Fn(&Socket) -> bool,
+    ) -> Result<Option<Socket>, io::Error> {
+        let (name, socket, used) = match self
+            .sockets
+            .iter_mut()
+            .find(|(_, socket, _)| socket.as_ref().is_some_and(&matches))
+        {
+            Some((name, Some(socket), used)) => (name, socket, used),
+            _ => return Ok(None),
+        };
+        log::info!("Using inherited socket {}", name);
+        *used = true;
+        socket.try_clone().map(Some)
+    }
+
+    pub fn tcp(&mut self, addr: SocketAddr) -> Result<Option<TcpListener>, io::Error> {
+        match self.duplicate(|socket| matches!(socket, Socket::Tcp(_, a) if *a == addr))? {
+            Some(Socket::Tcp(listener, _)) => Ok(Some(TcpListener::from_std(listener)?)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn udp(&mut self, addr: SocketAddr) -> Result<Option<UdpSocket>, io::Error> {
+        match self.duplicate(|socket| matches!(socket, Socket::Udp(_, a) if *a == addr))? {
+            Some(Socket::Udp(socket, _)) => Ok(Some(UdpSocket::from_std(socket)?)),
+            _ => Ok(None),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn unix(&mut self, path: &Path) -> Result<Option<tokio::net::UnixListener>, io::Error> {
+        match self.duplicate(|socket| matches!(socket, Socket::Unix(_, p) if p == path))? {
+            Some(Socket::Unix(listener, _)) => {
+                Ok(Some(tokio::net::UnixListener::from_std(listener)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Called by each server once it has bound its listeners. After the last one, unused sockets are closed,
+    /// and servers started later bind their own, unless they reuse one of the sockets that was used.
+    pub fn bound(&mut self) {
+        match self.unbound {
+            0 => {}
+            1 => {
+                self.unbound = 0;
+                self.close_unused();
+            }
+            _ => self.unbound -= 1,
+        }
+    }
+
+    fn close_unused(&mut self) {
+        for (name, socket, used) in &mut self.sockets {
+            if !*used && socket.take().is_some() {
+                log::warn!(
+                    "Inherited socket {} doesn't match any gateway or public address",
+                    name
+                );
+            }
+        }
+    }
+}
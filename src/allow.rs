@@ -0,0 +1,156 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Destinations which public SOCKS clients may connect to: `CIDR[:PORT[-PORT]]`,
+/// where IPv6 networks must be bracketed if followed by ports, e.g. `[fd00::/8]:22`.
+/// A bare address is a network of that single address, and a missing port range allows all ports.
+#[derive(Clone, Debug)]
+pub struct Allow {
+    network: IpAddr,
+    prefix_len: u8,
+    ports: RangeInclusive<u16>,
+}
+
+impl Allow {
+    pub fn permits(&self, addr: &SocketAddr) -> bool {
+        let in_network = match (self.network, addr.ip().to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        };
+        in_network && self.ports.contains(&addr.port())
+    }
+}
+
+impl FromStr for Allow {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+
+        let (cidr, ports) = if let Some(rest) = s.strip_prefix('[') {
+            match rest.split_once(']') {
+                Some((cidr, "")) => (cidr, None),
+                Some((cidr, ports)) => match ports.strip_prefix(':') {
+                    Some(ports) => (cidr, Some(ports)),
+                    None => return Err(invalid("Expected :PORT after ]")),
+                },
+                None => return Err(invalid("Expected ] after bracketed network")),
+            }
+        } else {
+            match s.matches(':').count() {
+                1 => {
+                    let (cidr, ports) = s.split_once(':').expect("contains a colon");
+                    (cidr, Some(ports))
+                }
+                _ => (s, None),
+            }
+        };
+
+        let (network, prefix_len) = match cidr.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (cidr, None),
+        };
+        let network = IpAddr::from_str(network).map_err(|_| invalid("Invalid network address"))?;
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(len) => match len.parse() {
+                Ok(len) if len <= max_len => len,
+                _ => return Err(invalid("Invalid network prefix length")),
+            },
+            None => max_len,
+        };
+
+        let port = |port: &str| port.parse::<u16>().map_err(|_| invalid("Invalid port"));
+        let ports = match ports {
+            None => 0..=u16::MAX,
+            Some(ports) => match ports.split_once('-') {
+                Some((start, end)) => port(start)?..=port(end)?,
+                None => {
+                    let port = port(ports)?;
+                    port..=port
+                }
+            },
+        };
+        if ports.is_empty() {
+            return Err(invalid("Empty port range"));
+        }
+
+        Ok(Allow {
+            network,
+            prefix_len,
+            ports,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permits(allow: &str, addr: &str) -> bool {
+        allow
+            .parse::<Allow>()
+            .unwrap()
+            .permits(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn networks() {
+        assert!(permits("10.0.0.0/8", "10.1.2.3:80"));
+        assert!(!permits("10.0.0.0/8", "11.1.2.3:80"));
+        assert!(permits("192.0.2.1", "192.0.2.1:1"));
+        assert!(!permits("192.0.2.1", "192.0.2.2:1"));
+        assert!(permits("0.0.0.0/0", "203.0.113.1:443"));
+        assert!(!permits("0.0.0.0/0", "[2001:db8::1]:443"));
+        assert!(permits("2001:db8::/32", "[2001:db8:1::1]:443"));
+        assert!(!permits("2001:db8::/32", "[2001:db9::1]:443"));
+        assert!(permits("::1", "[::1]:22"));
+        assert!(permits("::/0", "[2001:db8::1]:443"));
+        // IPv4-mapped addresses are matched as IPv4
+        assert!(permits("10.0.0.0/8", "[::ffff:10.0.0.1]:80"));
+    }
+
+    #[test]
+    fn ports() {
+        assert!(permits("192.0.2.0/24:22", "192.0.2.1:22"));
+        assert!(!permits("192.0.2.0/24:22", "192.0.2.1:23"));
+        assert!(permits("192.0.2.0/24:1000-2000", "192.0.2.1:1000"));
+        assert!(permits("192.0.2.0/24:1000-2000", "192.0.2.1:2000"));
+        assert!(!permits("192.0.2.0/24:1000-2000", "192.0.2.1:2001"));
+        assert!(permits("[fd00::/8]:22", "[fd12::1]:22"));
+        assert!(!permits("[fd00::/8]:22", "[fd12::1]:23"));
+        assert!(permits("[fd00::/8]", "[fd12::1]:23"));
+    }
+
+    #[test]
+    fn invalid() {
+        for allow in [
+            "",
+            "example.com",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/x",
+            "10.0.0.1:x",
+            "10.0.0.1:2000-1000",
+            "10.0.0.1:70000",
+            "[fd00::/8",
+            "[fd00::/8]22",
+        ] {
+            assert!(allow.parse::<Allow>().is_err(), "{}", allow);
+        }
+    }
+}
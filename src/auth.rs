@@ -0,0 +1,57 @@
+use crate::config::HANDSHAKE_TIMEOUT;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+const NONCE_LEN: usize = 32;
+const TAG_LEN: usize = 32;
+
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(secret: Vec<u8>) -> Self {
+        Secret(secret)
+    }
+
+    fn mac(&self, nonce: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts any key length");
+        mac.update(nonce);
+        mac
+    }
+}
+
+/// Server side: send a random nonce and verify that the peer answers with its HMAC.
+pub async fn challenge(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    secret: &Secret,
+) -> Result<(), io::Error> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)?;
+    stream.write_all(&nonce).await?;
+    stream.flush().await?;
+
+    let mut tag = [0; TAG_LEN];
+    timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut tag)).await??;
+    match secret.mac(&nonce).verify_slice(&tag) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Authentication failed",
+        )),
+    }
+}
+
+/// Client side: answer the server's nonce with its HMAC.
+pub async fn respond(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    secret: &Secret,
+) -> Result<(), io::Error> {
+    let mut nonce = [0; NONCE_LEN];
+    timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut nonce)).await??;
+
+    let tag = secret.mac(&nonce).finalize().into_bytes();
+    stream.write_all(&tag).await?;
+    stream.flush().await
+}
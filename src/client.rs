@@ -1,59 +1,631 @@
+use crate::allow::Allow;
+use crate::auth::{self, Secret};
 use crate::backoff::Backoff;
-use crate::config::CLIENT_BACKOFF_SECS;
+use crate::config::{CONNECT_ATTEMPT_DELAY, CONNECT_ATTEMPT_TIMEOUT, GATEWAY_RESOLVE_INTERVAL};
+use crate::datagram::{self, Activity, MAX_DATAGRAM_SIZE};
+use crate::endpoint::Endpoint;
 use crate::future::select_ok;
 use crate::heartbeat;
-use crate::magic;
+use crate::magic::{self, Hello, Protocol};
+use crate::mux;
+use crate::proxy;
+use crate::request::{self, Request, Target};
+use crate::resolve::Resolver;
 use crate::rw::conjoin;
+use crate::socks;
+use crate::tls::{self, Connector};
+use crate::transport::Gateway;
+use crate::tunnel::{self, Proxy};
+use crate::ws;
+use futures::future::{self, select, AbortHandle, Abortable, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+use pin_utils::pin_mut;
+use std::cell::Cell;
+use std::collections::hash_map::{Entry, HashMap};
+use std::convert::Infallible;
+use std::future::Future;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering::*};
 use std::time::Duration;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
 use tokio::task::LocalSet;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
 
+/// Connects to the first address that responds, trying the others in parallel if it's slow.
 async fn connect(addrs: &[SocketAddr]) -> Result<TcpStream, io::Error> {
-    let stream = select_ok(addrs.iter().map(TcpStream::connect)).await?;
+    let attempts = interleave(addrs).into_iter().map(|addr| async move {
+        timeout(CONNECT_ATTEMPT_TIMEOUT, TcpStream::connect(addr)).await?
+    });
+    let stream = select_ok(attempts, CONNECT_ATTEMPT_DELAY).await?;
     stream.set_nodelay(true)?;
     Ok(stream)
 }
 
+/// Alternates between address families, starting with the first, so that one broken family doesn't delay the other.
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_is_v4 = match addrs.first() {
+        Some(addr) => addr.is_ipv4(),
+        None => return Vec::new(),
+    };
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv4() == first_is_v4);
+    let mut second = second.into_iter();
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    for addr in first {
+        interleaved.push(addr);
+        interleaved.extend(second.next());
+    }
+    interleaved.extend(second);
+    interleaved
+}
+
+/// How gateway connections are made, and wrapped before the early handshake.
+pub struct Link {
+    /// Longest wait between failed connection attempts, in seconds.
+    pub max_backoff: u8,
+    /// Connect through an outbound proxy, see `tunnel`.
+    pub proxy: Option<Proxy<Resolver>>,
+    pub tls: Option<Connector>,
+    /// Send an HTTP Upgrade to WebSocket, inside TLS if enabled, see `ws`.
+    pub websocket: Option<ws::Url>,
+}
+
+async fn connect_gateway(
+    server: Server<'_>,
+    proxy: Option<&Proxy<Resolver>>,
+) -> Result<TcpStream, io::Error> {
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return connect(&server.addrs().await?).await,
+    };
+    // the proxy resolves the gateway's name, since it may be the only one able to
+    let mut gateway = connect(&proxy.addr.resolve().await?).await?;
+    tunnel::connect(&mut gateway, proxy, &server.target()).await?;
+    Ok(gateway)
+}
+
+async fn secure(gateway: TcpStream, tls: Option<&Connector>) -> Result<Gateway, io::Error> {
+    match tls {
+        Some(tls) => Ok(Box::new(tls::connect(tls, gateway).await?)),
+        None => Ok(Box::new(gateway)),
+    }
+}
+
+async fn upgrade(gateway: Gateway, websocket: Option<&ws::Url>) -> Result<Gateway, io::Error> {
+    match websocket {
+        Some(url) => Ok(Box::new(ws::connect(gateway, url).await?)),
+        None => Ok(gateway),
+    }
+}
+
+async fn early_handshake(
+    gateway: &mut Gateway,
+    secret: Option<&Secret>,
+    hello: &Hello,
+) -> Result<(), io::Error> {
+    if let Some(secret) = secret {
+        auth::respond(&mut *gateway, secret).await?;
+    }
+    magic::write_hello(gateway, hello).await
+}
+
+async fn late_handshake(gateway: &mut Gateway, secret: Option<&Secret>) -> Result<(), io::Error> {
+    match secret {
+        Some(secret) => auth::respond(gateway, secret).await,
+        None => magic::write_to(gateway).await,
+    }
+}
+
+async fn bind_connected(addr: SocketAddr) -> Result<UdpSocket, io::Error> {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Where to relay public connections for a service.
+pub struct Service {
+    pub dial: Dial,
+    /// Send a PROXY protocol header with the public connection's addresses before any data.
+    pub proxy: Option<proxy::Version>,
+}
+
+impl Service {
+    pub fn is_udp(&self) -> bool {
+        matches!(self.dial, Dial::Endpoint(Endpoint::Udp(_)))
+    }
+}
+
+pub enum Dial {
+    Endpoint(Endpoint<Resolver>),
+    /// Connect to the target of each request, if it's allowed, see `socks`.
+    Target(Vec<Allow>),
+}
+
+pub type Services = HashMap<String, Service>;
+
+pub fn services(
+    private: Vec<(String, Endpoint<String>)>,
+    socks: Vec<String>,
+    socks_allow: Vec<(String, Allow)>,
+    proxy_protocol: Vec<(String, proxy::Version)>,
+    dns_ttl: Option<Duration>,
+) -> Result<Services, io::Error> {
+    let mut services = private
+        .into_iter()
+        .map(|(name, endpoint)| {
+            let endpoint = match endpoint {
+                Endpoint::Tcp(host) => Endpoint::Tcp(Resolver::new(host, dns_ttl)),
+                Endpoint::Udp(host) => Endpoint::Udp(Resolver::new(host, dns_ttl)),
+                #[cfg(unix)]
+                Endpoint::Unix(path) => Endpoint::Unix(path),
+            };
+            (
+                name,
+                Service {
+                    dial: Dial::Endpoint(endpoint),
+                    proxy: None,
+                },
+            )
+        })
+        .collect::<Services>();
+    for name in socks {
+        if services.contains_key(&name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Service is both private and SOCKS: {}", name),
+            ));
+        }
+        let service = Service {
+            dial: Dial::Target(Vec::new()),
+            proxy: None,
+        };
+        services.insert(name, service);
+    }
+    for (name, allow) in socks_allow {
+        match services.get_mut(&name) {
+            Some(Service {
+                dial: Dial::Target(allowed),
+                ..
+            }) => allowed.push(allow),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("SOCKS destinations allowed for non-SOCKS service: {}", name),
+                ))
+            }
+        }
+    }
+    for (name, version) in proxy_protocol {
+        let service = match services.get_mut(&name) {
+            Some(service) => service,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("PROXY protocol enabled for unknown service: {}", name),
+                ))
+            }
+        };
+        if let Dial::Endpoint(Endpoint::Udp(_)) = service.dial {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("PROXY protocol is not supported for UDP service: {}", name),
+            ));
+        }
+        service.proxy = Some(version);
+    }
+    Ok(services)
+}
+
+fn lookup<'a>(services: &'a Services, request: &Request) -> Result<&'a Service, io::Error> {
+    let service = match services.get(&request.service) {
+        Some(service) => service,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Unknown service: {}", request.service),
+            ))
+        }
+    };
+    // datagrams can't be relayed to a stream, or the other way around
+    let mismatch = match (request.datagrams, service.is_udp()) {
+        (true, false) => "UDP public listener, but no UDP private endpoint",
+        (false, true) => "UDP private endpoint, but no UDP public listener",
+        _ => return Ok(service),
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} has a {}", request.service, mismatch),
+    ))
+}
+
+enum Private {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// Connects to a target, using only those of its addresses which are allowed.
+async fn connect_target(allowed: &[Allow], target: &Target) -> Result<TcpStream, io::Error> {
+    let addrs = lookup_host((target.host.as_str(), target.port)).await?;
+    let addrs = addrs
+        .filter(|addr| allowed.iter().any(|allow| allow.permits(addr)))
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Target not allowed: {}", target),
+        ));
+    }
+    connect(&addrs).await
+}
+
+async fn connect_private(service: &Service, request: &Request) -> Result<Private, io::Error> {
+    let mut private = match (&service.dial, &request.target) {
+        (Dial::Endpoint(endpoint), None) => match endpoint {
+            Endpoint::Tcp(private) => Private::Tcp(connect(&private.resolve().await?).await?),
+            // there's no connection to establish, so just use the first address
+            Endpoint::Udp(private) => {
+                Private::Udp(bind_connected(private.resolve().await?[0]).await?)
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Private::Unix(tokio::net::UnixStream::connect(path).await?),
+        },
+        (Dial::Target(allowed), Some(target)) => {
+            log::info!("Connecting to target {}", target);
+            Private::Tcp(connect_target(allowed, target).await?)
+        }
+        (Dial::Endpoint(_), Some(_)) | (Dial::Target(_), None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Request target doesn't match service: {}", request.service),
+            ))
+        }
+    };
+    if let Some(version) = service.proxy {
+        let header = proxy::header(version, request.addrs);
+        match &mut private {
+            Private::Tcp(private) => private.write_all(&header).await?,
+            Private::Udp(_) => unreachable!("PROXY protocol is rejected for UDP services"),
+            #[cfg(unix)]
+            Private::Unix(private) => private.write_all(&header).await?,
+        }
+    }
+    Ok(private)
+}
+
+/// Connects to private, and if the request has a target, tells the server whether that succeeded.
+async fn open_private(
+    gateway: impl AsyncWrite + Unpin,
+    service: &Service,
+    request: &Request,
+) -> Result<Private, io::Error> {
+    let private = connect_private(service, request).await;
+    if request.target.is_some() {
+        let status = match &private {
+            Ok(_) => socks::REP_SUCCEEDED,
+            Err(e) => socks::reply_code(e),
+        };
+        request::write_status(gateway, status).await?;
+    }
+    private
+}
+
+async fn relay(
+    gateway: impl AsyncRead + AsyncWrite + Unpin,
+    private: Private,
+    request: Request,
+    active: Rc<AtomicUsize>,
+) {
+    let service = request.service;
+    log::info!(
+        "Spawning {} ({} active)",
+        service,
+        active.fetch_add(1, Relaxed) + 1
+    );
+    let done = match private {
+        Private::Tcp(private) => conjoin(gateway, private).await,
+        Private::Udp(private) => relay_datagrams(gateway, private).await,
+        #[cfg(unix)]
+        Private::Unix(private) => conjoin(gateway, private).await,
+    };
+    let active = active.fetch_sub(1, Relaxed) - 1;
+    match done {
+        Ok((down, up)) => log::info!("Closing {} ({} active): {}/{}", service, active, down, up),
+        Err(e) => log::info!("Closing {} ({} active): {}", service, active, e),
+    }
+}
+
+async fn relay_datagrams(
+    gateway: impl AsyncRead + AsyncWrite,
+    private: UdpSocket,
+) -> Result<(u64, u64), io::Error> {
+    let (mut reader, mut writer) = tokio::io::split(gateway);
+    let activity = Activity::new();
+    let (down, up) = (Cell::new(0), Cell::new(0));
+    // an ICMP port unreachable for an earlier datagram is reported on the connected socket,
+    // but the private service may just not be listening yet, so only that datagram is lost
+    let refused = || log::debug!("Private refused a datagram");
+
+    let from_gateway = async {
+        let mut buf = Box::new([0; MAX_DATAGRAM_SIZE]);
+        while let Some(len) = datagram::read_from(&mut reader, &mut buf).await? {
+            activity.touch();
+            down.set(down.get() + len as u64);
+            match private.send(&buf[..len]).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => refused(),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    };
+    let to_gateway = async {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let len = match private.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    refused();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            activity.touch();
+            up.set(up.get() + len as u64);
+            datagram::write_to(&mut writer, &buf[..len]).await?;
+        }
+    };
+    let expired = async {
+        activity.expired().await;
+        Ok(())
+    };
+    pin_mut!(from_gateway);
+    pin_mut!(to_gateway);
+    pin_mut!(expired);
+    let (done, _, _) = future::select_all([
+        from_gateway as Pin<&mut dyn Future<Output = Result<(), io::Error>>>,
+        to_gateway,
+        expired,
+    ])
+    .await;
+    done.map(|()| (down.get(), up.get()))
+}
+
+/// Which servers to keep gateway connections to.
+pub enum Servers {
+    /// One server, reachable at any of the addresses its name resolves to.
+    Any(Resolver),
+    /// A separate server at each address its name resolves to, added and removed as that changes.
+    Each(Resolver),
+}
+
+/// Where a single gateway connection loop connects to.
+#[derive(Copy, Clone)]
+enum Server<'a> {
+    Any(&'a Resolver),
+    One(SocketAddr),
+}
+
+impl Server<'_> {
+    async fn addrs(self) -> Result<Vec<SocketAddr>, io::Error> {
+        match self {
+            Server::Any(resolver) => resolver.resolve().await,
+            Server::One(addr) => Ok(vec![addr]),
+        }
+    }
+
+    fn target(self) -> Target {
+        match self {
+            Server::Any(resolver) => resolver.target(),
+            Server::One(addr) => Target {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+            },
+        }
+    }
+}
+
 pub async fn run(
     local: &LocalSet,
-    gateway_addrs: &[SocketAddr],
-    private_addrs: &[SocketAddr],
+    servers: Servers,
+    services: Services,
+    secret: Option<Secret>,
+    link: Link,
+    hello: Hello,
+    pool_size: usize,
 ) -> ! {
-    let mut backoff = Backoff::new(CLIENT_BACKOFF_SECS);
     let active = Rc::new(AtomicUsize::new(0));
+    let services = Rc::new(services);
+
+    // each gateway connection gets its own retry loop, so that they're reestablished in parallel,
+    // and each server gets its own pool, so that they all have gateways available
+    let gateways_to = |server| {
+        keep_connected(
+            local,
+            server,
+            &services,
+            secret.as_ref(),
+            &link,
+            &hello,
+            &active,
+        )
+    };
+
+    let resolver = match servers {
+        Servers::Any(resolver) => {
+            let gateways = (0..pool_size).map(|_| Box::pin(gateways_to(Server::Any(&resolver))));
+            let (i, _, _) = future::select_all(gateways).await;
+            match i {}
+        }
+        Servers::Each(resolver) => resolver,
+    };
+
+    let interval = resolver.ttl().unwrap_or(GATEWAY_RESOLVE_INTERVAL);
+    let mut running = FuturesUnordered::new();
+    let mut pools = HashMap::<SocketAddr, Vec<AbortHandle>>::new();
+    loop {
+        match resolver.resolve().await {
+            Ok(addrs) => {
+                pools.retain(|addr, pool| {
+                    let keep = addrs.contains(addr);
+                    if !keep {
+                        log::info!("Closing gateway connections to {}", addr);
+                        pool.iter().for_each(AbortHandle::abort);
+                    }
+                    keep
+                });
+                for addr in addrs {
+                    if let Entry::Vacant(entry) = pools.entry(addr) {
+                        let pool = entry.insert(Vec::new());
+                        for _ in 0..pool_size {
+                            let (handle, registration) = AbortHandle::new_pair();
+                            running
+                                .push(Abortable::new(gateways_to(Server::One(addr)), registration));
+                            pool.push(handle);
+                        }
+                    }
+                }
+            }
+            Err(e) => log::error!("Failed to resolve gateway: {}", e),
+        }
+
+        // aborted loops finish here, and the others never do
+        let next_resolve = sleep(interval);
+        pin_mut!(next_resolve);
+        loop {
+            if running.is_empty() {
+                next_resolve.await;
+                break;
+            }
+            if let Either::Left(_) = select(&mut next_resolve, running.next()).await {
+                break;
+            }
+        }
+    }
+}
+
+async fn keep_connected(
+    local: &LocalSet,
+    server: Server<'_>,
+    services: &Rc<Services>,
+    secret: Option<&Secret>,
+    link: &Link,
+    hello: &Hello,
+    active: &Rc<AtomicUsize>,
+) -> Infallible {
+    let mut backoff = Backoff::new(1..=link.max_backoff);
 
     loop {
         let one_round = async {
-            log::info!("Connecting to gateway");
-            let mut gateway = connect(gateway_addrs).await?;
+            match &link.proxy {
+                Some(proxy) => log::info!("Connecting to gateway through {:?} proxy", proxy.kind),
+                None => log::info!("Connecting to gateway"),
+            }
+            let gateway = connect_gateway(server, link.proxy.as_ref()).await?;
+
+            if link.tls.is_some() {
+                log::info!("Starting TLS");
+            }
+            let gateway = secure(gateway, link.tls.as_ref()).await?;
+
+            if link.websocket.is_some() {
+                log::info!("Upgrading to WebSocket");
+            }
+            let mut gateway = upgrade(gateway, link.websocket.as_ref()).await?;
 
             log::info!("Sending early handshake");
-            magic::write_to(&mut gateway).await?;
+            early_handshake(&mut gateway, secret, hello).await?;
+
+            if let Protocol::Multiplexed = hello.protocol {
+                log::info!("Multiplexed session started");
+                backoff.reset();
+
+                let (session, driver) = mux::Session::new(gateway, mux::Role::Client);
+                let accept_streams = async {
+                    loop {
+                        let stream = match session.accept().await {
+                            Ok(stream) => stream,
+                            Err(e) => return e,
+                        };
+                        log::info!("Accepted multiplexed stream");
+                        let services = services.clone();
+                        let active = active.clone();
+                        local.spawn_local(async move {
+                            let mut stream = stream;
+                            let request = match request::read_from(&mut stream).await {
+                                Ok(request) => request,
+                                Err(e) => {
+                                    log::warn!("Failed to read request: {}", e);
+                                    return;
+                                }
+                            };
+                            let private = match lookup(&services, &request) {
+                                Ok(private) => private,
+                                Err(e) => {
+                                    log::warn!("Rejecting stream: {}", e);
+                                    return;
+                                }
+                            };
+                            match open_private(&mut stream, private, &request).await {
+                                Ok(private) => relay(stream, private, request, active).await,
+                                Err(e) => log::warn!("Failed to connect to private: {}", e),
+                            }
+                        });
+                    }
+                };
+                // the driver outlives this loop, so that open streams finish when the client stops
+                let driver = local.spawn_local(driver);
+                pin_mut!(accept_streams);
+                return match select(driver, accept_streams).await {
+                    // the server stopped using this session, so a new one can be started right away
+                    _ if session.is_going_away() => {
+                        log::info!("Server is going away");
+                        Ok(())
+                    }
+                    Either::Left((Ok(e), _)) | Either::Right((e, _)) => Err(e),
+                    Either::Left((Err(e), _)) => Err(io::Error::other(e)),
+                };
+            }
 
             log::info!("Waiting for end of heartbeat");
             heartbeat::read_from(&mut gateway).await?;
 
+            // if the service is unknown or doesn't match, skip the late handshake, so the server tries another gateway
+            log::info!("Reading request");
+            let request = request::read_from(&mut gateway).await?;
+            let private = match lookup(services, &request) {
+                Ok(private) => private,
+                // the gateway itself is fine, so don't back off
+                Err(e) => {
+                    log::warn!("Rejecting request: {}", e);
+                    return Ok(());
+                }
+            };
+
             log::info!("Sending late handshake");
-            magic::write_to(&mut gateway).await?;
-
-            log::info!("Connecting to private");
-            let private = connect(private_addrs).await?;
-
-            log::info!("Spawning ({} active)", active.fetch_add(1, Relaxed) + 1);
-            let active = active.clone();
-            local.spawn_local(async move {
-                let done = conjoin(gateway, private).await;
-                let active = active.fetch_sub(1, Relaxed) - 1;
-                match done {
-                    Ok((down, up)) => log::info!("Closing ({} active): {}/{}", active, down, up),
-                    Err(e) => log::info!("Closing ({} active): {}", active, e),
+            late_handshake(&mut gateway, secret).await?;
+
+            log::info!("Connecting to private for {}", request.service);
+            let private = match open_private(&mut gateway, private, &request).await {
+                Ok(private) => private,
+                // the server has been told, and the gateway itself is fine, so don't back off
+                Err(e) if request.target.is_some() => {
+                    log::warn!("Failed to connect to target: {}", e);
+                    return Ok(());
                 }
-            });
+                Err(e) => return Err(e),
+            };
+
+            local.spawn_local(relay(gateway, private, request, active.clone()));
 
             Ok::<(), io::Error>(())
         }
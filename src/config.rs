@@ -4,9 +4,22 @@ use std::time::Duration;
 pub const MIN_BUFFER_SIZE: usize = 4 * 1024;
 pub const MAX_BUFFER_SIZE: usize = 2 * 1024 * 1024;
 
-pub const QUEUE_TIMEOUT: Duration = Duration::from_secs(60);
 pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const TARGET_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connection attempts to each address are started this far apart, like RFC 8305 recommends.
+pub const CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+pub const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// With --all-gateways and no DNS TTL, how often to check whether the gateway's addresses changed.
+pub const GATEWAY_RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
 
 pub const SERVER_ACCEPT_BACKOFF_SECS: RangeInclusive<u8> = 1..=64;
-pub const CLIENT_BACKOFF_SECS: RangeInclusive<u8> = 1..=64;
+
+pub const MUX_WINDOW_SIZE: u32 = 256 * 1024;
+pub const MUX_MAX_FRAME_SIZE: u32 = 16 * 1024;
+/// Bytes of data frames waiting to be sent on a session before streams stop accepting writes.
+pub const MUX_MAX_OUTGOING: usize = 1024 * 1024;
+
+pub const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+pub const UDP_SESSION_QUEUE: usize = 64;
@@ -0,0 +1,63 @@
+//! Datagrams are carried over gateway connections with a 2-byte big-endian length prefix.
+
+use crate::config::UDP_SESSION_TIMEOUT;
+use std::cell::Cell;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep_until, Instant};
+
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// Reads one datagram into `buf`, returning its length, or `None` if the gateway was closed cleanly.
+pub async fn read_from(
+    mut reader: impl AsyncRead + Unpin,
+    buf: &mut [u8; MAX_DATAGRAM_SIZE],
+) -> Result<Option<usize>, io::Error> {
+    let mut len = [0; 2];
+    match reader.read(&mut len[..1]).await? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut len[1..]).await?,
+    };
+    let len = usize::from(u16::from_be_bytes(len));
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok(Some(len))
+}
+
+pub async fn write_to(
+    mut writer: impl AsyncWrite + Unpin,
+    datagram: &[u8],
+) -> Result<(), io::Error> {
+    let len = match datagram.len() {
+        len @ 0..=MAX_DATAGRAM_SIZE => len as u16,
+        _ => return Err(io::ErrorKind::InvalidInput.into()),
+    };
+    let mut buf = Vec::with_capacity(2 + datagram.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(datagram);
+    writer.write_all(&buf).await?;
+    writer.flush().await
+}
+
+/// Tracks when a datagram was last relayed in either direction, since there's no other way to tell that a session is over.
+pub struct Activity(Cell<Instant>);
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity(Cell::new(Instant::now()))
+    }
+
+    pub fn touch(&self) {
+        self.0.set(Instant::now());
+    }
+
+    /// Completes once no datagrams have been relayed for `UDP_SESSION_TIMEOUT`.
+    pub async fn expired(&self) {
+        loop {
+            let deadline = self.0.get() + UDP_SESSION_TIMEOUT;
+            if deadline <= Instant::now() {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::fmt::{self, Display};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// Where public traffic is received, or private traffic is relayed to.
+#[derive(Clone, Debug)]
+pub enum Endpoint<A> {
+    Tcp(A),
+    /// Datagrams are relayed over the gateway, see `datagram`.
+    Udp(A),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Display for Endpoint<std::net::SocketAddr> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Udp(addr) => write!(f, "udp:{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
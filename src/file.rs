@@ -0,0 +1,364 @@
+//! Tunnels declared in a TOML file, instead of a single one on the command line.
+//!
+//! Each `[[server]]` or `[[client]]` table holds that subcommand's arguments, keyed by their long names,
+//! or for positional arguments, by their names in lowercase. Values are parsed exactly like the command line,
+//! with arrays for repeated arguments, and booleans for flags.
+
+use crate::opt::{Mode, Options};
+use clap::error::{ContextKind, ContextValue};
+use clap::{Arg, Command, CommandFactory, Parser};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::iter;
+use std::ops::Range;
+use std::path::Path;
+use toml_edit::{ImDocument, Item, Table, Value};
+
+/// Points errors at a location in the file.
+struct Source<'a> {
+    path: &'a Path,
+    text: &'a str,
+}
+
+impl Source<'_> {
+    fn error(&self, span: Option<Range<usize>>, msg: impl Display) -> io::Error {
+        let msg = match span {
+            Some(span) => {
+                let before = &self.text[..span.start];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                format!("{}:{}:{}: {}", self.path.display(), line, column, msg)
+            }
+            None => format!("{}: {}", self.path.display(), msg),
+        };
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    }
+}
+
+/// A tunnel declared in the file.
+pub struct Tunnel {
+    /// Like `server[0]`, for the order it's declared in.
+    pub name: String,
+    /// The command line it was parsed from, which identifies it across reloads.
+    pub args: Vec<String>,
+    pub mode: Mode,
+}
+
+/// Reads every tunnel in the file at `path`.
+pub fn load(path: &Path) -> Result<Vec<Tunnel>, io::Error> {
+    parse(path, &fs::read_to_string(path)?)
+}
+
+/// Parses the tunnels in `text`, read from `path`.
+fn parse(path: &Path, text: &str) -> Result<Vec<Tunnel>, io::Error> {
+    let doc = ImDocument::parse(text).map_err(|e| {
+        let msg = e.to_string();
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: {}", path.display(), msg.trim_end()),
+        )
+    })?;
+    let source = Source { path, text };
+
+    let mut command = Options::command();
+    command.build();
+
+    let mut tunnels = Vec::new();
+    for (kind, item) in doc.iter() {
+        let key_span = doc.key(kind).and_then(|key| key.span());
+        let subcommand = match command.find_subcommand(kind) {
+            Some(subcommand) => subcommand,
+            None => {
+                return Err(source.error(
+                    key_span,
+                    format!("unknown key `{}`, expected [[server]] or [[client]]", kind),
+                ))
+            }
+        };
+        let tables = match item.as_array_of_tables() {
+            Some(tables) => tables,
+            None => return Err(source.error(key_span, format!("expected [[{}]] tables", kind))),
+        };
+        for (i, table) in tables.iter().enumerate() {
+            let name = format!("{}[{}]", kind, i);
+            let located = |span, msg: String| source.error(span, format!("{}: {}", name, msg));
+            let args = iter::once(kind.to_string())
+                .chain(args(subcommand, table).map_err(|(span, msg)| located(span, msg))?)
+                .collect::<Vec<_>>();
+            let options = Options::try_parse_from(
+                iter::once("relayed".to_string()).chain(args.iter().cloned()),
+            )
+            .map_err(|e| {
+                let (span, msg) = describe(subcommand, table, &e);
+                located(span, msg)
+            })?;
+            let mode = options.mode.expect("subcommand was given");
+            tunnels.push(Tunnel { name, args, mode });
+        }
+    }
+    if tunnels.is_empty() {
+        return Err(source.error(None, "no [[server]] or [[client]] tunnels"));
+    }
+    Ok(tunnels)
+}
+
+/// The key for an argument, if it can be given in the file.
+fn key(arg: &Arg) -> Option<&str> {
+    if arg.is_global_set() {
+        return None;
+    }
+    match arg.get_long() {
+        Some("help" | "version") => None,
+        Some(long) => Some(long),
+        None if arg.is_positional() => Some(arg.get_id().as_str()),
+        None => None,
+    }
+}
+
+type Located = (Option<Range<usize>>, String);
+
+/// Converts a table into command line arguments, options first, then positional arguments.
+fn args(subcommand: &Command, table: &Table) -> Result<Vec<String>, Located> {
+    let key_span = |key: &str| table.key(key).and_then(|key| key.span());
+
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    for (k, item) in table.iter() {
+        let arg = match subcommand.get_arguments().find(|arg| key(arg) == Some(k)) {
+            Some(arg) => arg,
+            None => return Err((key_span(k), format!("unknown key `{}`", k))),
+        };
+        let invalid = |msg: &str| {
+            (
+                item.span().or_else(|| key_span(k)),
+                format!("`{}`: {}", k, msg),
+            )
+        };
+
+        if !arg.get_action().takes_values() {
+            match item.as_bool() {
+                Some(true) => options.push(format!("--{}", k)),
+                Some(false) => {}
+                None => return Err(invalid("expected true or false")),
+            }
+            continue;
+        }
+
+        let values = values(item).map_err(invalid)?;
+        // a delimited argument takes all of its values at once
+        let values = match arg.get_value_delimiter() {
+            Some(delimiter) => vec![values.join(&delimiter.to_string())],
+            None => values,
+        };
+        if arg.is_positional() {
+            positionals.push((arg.get_index(), values));
+        } else {
+            options.extend(values.into_iter().map(|value| format!("--{}={}", k, value)));
+        }
+    }
+
+    // positional arguments are assigned in order, so a missing one would shift the later ones into its place
+    let last = positionals.iter().filter_map(|(index, _)| *index).max();
+    for arg in subcommand.get_positionals() {
+        match key(arg) {
+            Some(k) if arg.get_index() < last && !table.contains_key(k) => {
+                return Err((table.span(), format!("missing key `{}`", k)))
+            }
+            _ => {}
+        }
+    }
+    positionals.sort_by_key(|(index, _)| *index);
+    options.push("--".to_string());
+    options.extend(positionals.into_iter().flat_map(|(_, values)| values));
+    Ok(options)
+}
+
+fn values(item: &Item) -> Result<Vec<String>, &'static str> {
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Ok(s.value().clone()),
+        Value::Integer(i) => Ok(i.value().to_string()),
+        Value::Boolean(b) => Ok(b.value().to_string()),
+        _ => Err("expected a string, integer, boolean, or an array of them"),
+    };
+    match item.as_value() {
+        Some(Value::Array(array)) => array.iter().map(scalar).collect(),
+        Some(value) => Ok(vec![scalar(value)?]),
+        None => Err("expected a string, integer, boolean, or an array of them"),
+    }
+}
+
+/// Rewrites a command line parsing error in terms of keys, and finds the key it's about.
+fn describe(subcommand: &Command, table: &Table, e: &clap::Error) -> Located {
+    let rendered = e.render().to_string();
+    let rendered = rendered.trim_start_matches("error: ");
+    // drop the usage and help hints, and join lists of arguments onto one line
+    let first_paragraph = rendered.split("\n\n").next().unwrap_or_default();
+    let mut msg = first_paragraph
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut span = table.span();
+    let invalid_arg = match e.get(ContextKind::InvalidArg) {
+        Some(ContextValue::String(arg)) => Some(arg.as_str()),
+        Some(ContextValue::Strings(args)) => args.first().map(String::as_str),
+        _ => None,
+    };
+    for arg in subcommand.get_arguments() {
+        let k = match key(arg) {
+            Some(k) => k,
+            None => continue,
+        };
+        // errors show positional arguments differently when they're required
+        let mut displayed = vec![arg.to_string()];
+        if arg.is_positional() {
+            let name = k.to_uppercase();
+            displayed.extend([format!("<{}>...", name), format!("<{}>", name)]);
+        }
+        for displayed in displayed {
+            if invalid_arg == Some(displayed.as_str()) {
+                if let Some(key_span) = table.key(k).and_then(|key| key.span()) {
+                    span = Some(key_span);
+                }
+            }
+            msg = msg
+                .replace(&format!("'{}'", displayed), &format!("`{}`", k))
+                .replace(&displayed, &format!("`{}`", k));
+        }
+    }
+    (span, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        match parse(Path::new("relayed.toml"), text) {
+            Ok(_) => panic!("parsed {:?}", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn args_from_table() {
+        let doc = ImDocument::parse(
+            "[[server]]\n\
+             public = [\"127.0.0.1:8080\", \"b=127.0.0.1:8081\"]\n\
+             websocket = true\n\
+             accept-proxy-protocol = false\n\
+             pool-size = 4\n\
+             route = [\"a.example=a\", \"b.example=b\"]\n\
+             gateway = [\"127.0.0.1:9000\", \"[::1]:9000\"]\n",
+        )
+        .unwrap();
+        let table = doc["server"].as_array_of_tables().unwrap().get(0).unwrap();
+        let mut command = Options::command();
+        command.build();
+        let subcommand = command.find_subcommand("server").unwrap();
+        assert_eq!(
+            args(subcommand, table).unwrap(),
+            [
+                "--websocket",
+                "--pool-size=4",
+                "--route=a.example=a",
+                "--route=b.example=b",
+                "--",
+                "127.0.0.1:9000,[::1]:9000",
+                "127.0.0.1:8080",
+                "b=127.0.0.1:8081",
+            ]
+        );
+    }
+
+    #[test]
+    fn values_of_items() {
+        let doc = ImDocument::parse(
+            "string = \"a\"\n\
+             integer = 1\n\
+             boolean = true\n\
+             array = [\"a\", 1, false]\n\
+             float = 1.5\n\
+             nested = [[\"a\"]]\n\
+             table = { a = 1 }\n",
+        )
+        .unwrap();
+        assert_eq!(values(&doc["string"]).unwrap(), ["a"]);
+        assert_eq!(values(&doc["integer"]).unwrap(), ["1"]);
+        assert_eq!(values(&doc["boolean"]).unwrap(), ["true"]);
+        assert_eq!(values(&doc["array"]).unwrap(), ["a", "1", "false"]);
+        for key in ["float", "nested", "table"] {
+            assert!(values(&doc[key]).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn load_tunnels() {
+        let tunnels = parse(
+            Path::new("relayed.toml"),
+            "[[server]]\n\
+             gateway = \"127.0.0.1:9000\"\n\
+             public = [\"127.0.0.1:8080\"]\n\
+             \n\
+             [[client]]\n\
+             gateway = \"example.com:9000\"\n\
+             private = [\"127.0.0.1:80\"]\n\
+             \n\
+             [[client]]\n\
+             gateway = \"example.com:9000\"\n\
+             private = [\"127.0.0.1:81\"]\n\
+             mux = true\n",
+        )
+        .unwrap();
+        let names = tunnels.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["server[0]", "client[0]", "client[1]"]);
+        assert_eq!(
+            tunnels[2].args,
+            ["client", "--mux", "--", "example.com:9000", "127.0.0.1:81"]
+        );
+        assert!(matches!(tunnels[0].mode, Mode::Server { .. }));
+    }
+
+    #[test]
+    fn error_locations() {
+        // a positional argument before a given one
+        assert_eq!(
+            error("[[server]]\npublic = [\"127.0.0.1:8080\"]\n"),
+            "relayed.toml:1:1: server[0]: missing key `gateway`"
+        );
+        // required arguments, reported by clap
+        assert_eq!(
+            error("[[client]]\nsecret = \"x\"\n"),
+            "relayed.toml:1:1: client[0]: the following required arguments were not provided: `gateway` `private`"
+        );
+        assert_eq!(
+            error("[[server]]\ngateway = \"127.0.0.1:9000\"\nbogus = 1\n"),
+            "relayed.toml:3:1: server[0]: unknown key `bogus`"
+        );
+        assert_eq!(
+            error("[[server]]\ngateway = \"127.0.0.1:9000\"\npublic = [1.5]\n"),
+            "relayed.toml:3:10: server[0]: `public`: expected a string, integer, boolean, or an array of them"
+        );
+        assert_eq!(
+            error("[[server]]\ngateway = \"127.0.0.1:9000\"\npublic = [\"127.0.0.1:8080\"]\nwebsocket = \"yes\"\n"),
+            "relayed.toml:4:13: server[0]: `websocket`: expected true or false"
+        );
+        assert_eq!(
+            error("[[server]]\ngateway = \"127.0.0.1:9000\"\npool-size = \"zero\"\npublic = [\"127.0.0.1:8080\"]\n"),
+            "relayed.toml:3:1: server[0]: invalid value 'zero' for `pool-size`: invalid digit found in string"
+        );
+        assert_eq!(
+            error("[[server]]\ngateway = \"127.0.0.1:9000\"\npublic = [\"127.0.0.1:8080\"]\n\n[[server]]\ngateway = \"nowhere\"\npublic = [\"127.0.0.1:8081\"]\n"),
+            "relayed.toml:6:1: server[1]: invalid value 'nowhere' for `gateway`: invalid socket address syntax"
+        );
+        assert_eq!(
+            error("[[proxy]]\n"),
+            "relayed.toml:1:3: unknown key `proxy`, expected [[server]] or [[client]]"
+        );
+        assert_eq!(
+            error(""),
+            "relayed.toml: no [[server]] or [[client]] tunnels"
+        );
+    }
+}
@@ -1,18 +1,88 @@
+use futures::future::{select, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+use pin_utils::pin_mut;
 use std::future::Future;
+use std::time::Duration;
+use tokio::time::sleep;
 
-pub async fn select_ok<T, E, F>(iter: impl IntoIterator<Item = F>) -> Result<T, E>
+/// Runs futures in order, starting each one after the previous has failed or `stagger` has passed,
+/// whichever comes first, and returns the first success, dropping the others (as in RFC 8305).
+pub async fn select_ok<T, E, F>(
+    iter: impl IntoIterator<Item = F>,
+    stagger: Duration,
+) -> Result<T, E>
 where
     F: Future<Output = Result<T, E>>,
 {
+    let mut pending = iter.into_iter();
+    let mut running = FuturesUnordered::new();
     let mut last_error = None;
-    for fut in iter {
-        match fut.await {
-            Ok(x) => return Ok(x),
-            Err(e) => last_error = Some(e),
+    loop {
+        let started = match pending.next() {
+            Some(fut) => {
+                running.push(fut);
+                true
+            }
+            None => false,
+        };
+        if running.is_empty() {
+            match last_error {
+                Some(e) => return Err(e),
+                None => panic!("select_ok: no elements"),
+            }
+        }
+
+        let done = match started {
+            true => {
+                let delay = sleep(stagger);
+                pin_mut!(delay);
+                match select(running.next(), delay).await {
+                    Either::Left((done, _)) => done,
+                    Either::Right(((), _)) => continue,
+                }
+            }
+            // nothing left to start, so just wait
+            false => running.next().await,
+        };
+        match done {
+            Some(Ok(x)) => return Ok(x),
+            Some(Err(e)) => last_error = Some(e),
+            None => unreachable!("at least one future is running"),
         }
     }
-    match last_error {
-        Some(e) => Err(e),
-        None => panic!("select_ok: no elements"),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    /// Runs attempts which each take some milliseconds and then succeed or fail,
+    /// returning the result and the milliseconds it took.
+    async fn run(attempts: &[(u64, Result<u32, u32>)], stagger: u64) -> (Result<u32, u32>, u128) {
+        let start = Instant::now();
+        let attempts = attempts.iter().map(|&(ms, result)| async move {
+            sleep(Duration::from_millis(ms)).await;
+            result
+        });
+        let result = select_ok(attempts, Duration::from_millis(stagger)).await;
+        (result, start.elapsed().as_millis())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn staggers_attempts() {
+        // the first attempt succeeds before the next is started
+        assert_eq!(run(&[(100, Ok(1)), (0, Ok(2))], 250).await, (Ok(1), 100));
+        // a slow attempt doesn't hold up the next, which starts after the stagger
+        assert_eq!(run(&[(1000, Ok(1)), (100, Ok(2))], 250).await, (Ok(2), 350));
+        // but it can still win
+        assert_eq!(run(&[(300, Ok(1)), (100, Ok(2))], 250).await, (Ok(1), 300));
+        // a failure starts the next attempt right away
+        assert_eq!(run(&[(50, Err(1)), (100, Ok(2))], 250).await, (Ok(2), 150));
+        // if all fail, the error of the last to finish is returned
+        assert_eq!(
+            run(&[(50, Err(1)), (300, Err(2)), (100, Err(3))], 250).await,
+            (Err(3), 400)
+        );
     }
 }
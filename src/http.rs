@@ -0,0 +1,224 @@
+//! Just enough HTTP/1.x to route requests by Host header, and to upgrade connections, see `ws`.
+
+use crate::config::HANDSHAKE_TIMEOUT;
+use std::io;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// Request heads are limited to a reasonable size, like most HTTP servers do.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Responses to requests which aren't relayed.
+#[derive(Copy, Clone, Debug)]
+pub enum Status {
+    BadRequest,
+    NotFound,
+    BadGateway,
+}
+
+impl FromStr for Status {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "404" => Ok(Status::NotFound),
+            "502" => Ok(Status::BadGateway),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected 404 or 502",
+            )),
+        }
+    }
+}
+
+pub async fn write_response(
+    mut writer: impl AsyncWrite + Unpin,
+    status: Status,
+) -> Result<(), io::Error> {
+    let status = match status {
+        Status::BadRequest => "400 Bad Request",
+        Status::NotFound => "404 Not Found",
+        Status::BadGateway => "502 Bad Gateway",
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.flush().await
+}
+
+/// Reads the first request head from a connection, returning the host it's for, if any.
+/// Everything read is appended to `peeked`, so that it can be replayed afterwards.
+pub async fn read_host(
+    mut reader: impl AsyncRead + Unpin,
+    peeked: &mut Vec<u8>,
+) -> Result<Option<String>, io::Error> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        loop {
+            if let Some(end) = peeked.windows(4).position(|w| w == b"\r\n\r\n") {
+                return host(&peeked[..end]);
+            }
+            if peeked.len() >= MAX_HEAD_SIZE {
+                return Err(invalid("Request head too large"));
+            }
+            if reader.read_buf(peeked).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    })
+    .await?
+}
+
+/// Reads a request or response head, without reading past its end, returning it without the final blank line.
+pub async fn read_head(mut reader: impl AsyncRead + Unpin) -> Result<String, io::Error> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_HEAD_SIZE {
+                return Err(invalid("Head too large"));
+            }
+            head.push(reader.read_u8().await?);
+        }
+        head.truncate(head.len() - 4);
+        String::from_utf8(head).map_err(|_| invalid("Invalid head"))
+    })
+    .await?
+}
+
+/// Header names and values, in the order they were sent.
+pub type Headers<'a> = Vec<(&'a str, &'a str)>;
+
+/// Splits a head into its first line, and validates the header lines which follow.
+pub fn parse_head(head: &str) -> Result<(&str, Headers<'_>), io::Error> {
+    let mut lines = head.split("\r\n");
+    let first_line = lines.next().unwrap_or_default();
+    let mut headers = Vec::new();
+    for line in lines {
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name, value.trim())),
+            None => return Err(invalid("Invalid header")),
+        }
+    }
+    Ok((first_line, headers))
+}
+
+/// The value of the first header called `name`, ignoring case.
+pub fn header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| *value)
+}
+
+fn host(head: &[u8]) -> Result<Option<String>, io::Error> {
+    let head = std::str::from_utf8(head).map_err(|_| invalid("Invalid request head"))?;
+    let (request_line, headers) = parse_head(head)?;
+    if !request_line.ends_with(" HTTP/1.0") && !request_line.ends_with(" HTTP/1.1") {
+        return Err(invalid("Not an HTTP/1.x request"));
+    }
+    // HTTP/1.0 clients may not send a Host header
+    Ok(header(&headers, "host").map(|host| without_port(host).to_string()))
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize])),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+fn without_port(host: &str) -> &str {
+    // IPv6 literals are bracketed, since they contain colons
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    match host.split_once(':') {
+        Some((host, _)) => host,
+        None => host,
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_host() {
+        let host = |head: &str| host(head.as_bytes());
+        assert_eq!(
+            host("GET / HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*")
+                .unwrap()
+                .as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            host("GET / HTTP/1.1\r\nhOST: [2001:db8::1]:8080")
+                .unwrap()
+                .as_deref(),
+            Some("[2001:db8::1]")
+        );
+        assert_eq!(host("GET / HTTP/1.0").unwrap(), None);
+        assert!(host("GET / HTTP/2").is_err());
+        assert!(host("GET / HTTP/1.1\r\nHost example.com").is_err());
+    }
+
+    #[test]
+    fn host_without_port() {
+        assert_eq!(without_port("example.com"), "example.com");
+        assert_eq!(without_port("example.com:80"), "example.com");
+        assert_eq!(without_port("192.0.2.1:80"), "192.0.2.1");
+        assert_eq!(without_port("[2001:db8::1]:80"), "[2001:db8::1]");
+        assert_eq!(without_port("[2001:db8::1]"), "[2001:db8::1]");
+        assert_eq!(without_port("[2001:db8::1"), "[2001:db8::1");
+    }
+
+    #[test]
+    fn base64_encoding() {
+        // test vectors from RFC 4648
+        for (input, output) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(input.as_bytes()), output);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_host_peeks_head() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\nbody";
+        let mut peeked = Vec::new();
+        let host = read_host(&request[..], &mut peeked).await.unwrap();
+        assert_eq!(host.as_deref(), Some("example.com"));
+        assert_eq!(peeked, request);
+
+        let e = read_host(&request[..20], &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
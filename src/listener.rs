@@ -0,0 +1,138 @@
+use crate::proxy::Addrs;
+use futures::ready;
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Something connections can be accepted from, see `server::accept`.
+pub trait Listener {
+    type Stream: AsyncRead + AsyncWrite + Unpin + 'static;
+    type Addr;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<Accepted<Self>, io::Error>>;
+
+    /// Addresses of an accepted connection, if it has any.
+    fn addrs(stream: &Self::Stream, addr: &Self::Addr) -> Option<Addrs>;
+}
+
+/// An accepted connection and the address of its peer.
+pub type Accepted<L> = (<L as Listener>::Stream, <L as Listener>::Addr);
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Addr = SocketAddr;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<Accepted<Self>, io::Error>> {
+        loop {
+            let (stream, addr) = ready!(TcpListener::poll_accept(self, cx))?;
+            match stream.set_nodelay(true) {
+                Ok(()) => return Poll::Ready(Ok((stream, addr))),
+                Err(e) => log::warn!("Failed to set nodelay: {}", e),
+            }
+        }
+    }
+
+    fn addrs(stream: &Self::Stream, addr: &Self::Addr) -> Option<Addrs> {
+        match stream.local_addr() {
+            Ok(destination) => Some(Addrs {
+                source: *addr,
+                destination,
+            }),
+            Err(e) => {
+                log::warn!("Failed to get local address: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+    type Addr = tokio::net::unix::SocketAddr;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<Accepted<Self>, io::Error>> {
+        tokio::net::UnixListener::poll_accept(self, cx)
+    }
+
+    fn addrs(_: &Self::Stream, _: &Self::Addr) -> Option<Addrs> {
+        None
+    }
+}
+
+/// Accepts from whichever listener is ready first.
+impl<L: Listener> Listener for Vec<L> {
+    type Stream = L::Stream;
+    type Addr = L::Addr;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<Accepted<Self>, io::Error>> {
+        for i in 0..self.len() {
+            if let Poll::Ready(accepted) = self[i].poll_accept(cx) {
+                // move it to the back, so that a busy listener can't starve the others
+                self[i..].rotate_left(1);
+                return Poll::Ready(accepted);
+            }
+        }
+        Poll::Pending
+    }
+
+    fn addrs(stream: &Self::Stream, addr: &Self::Addr) -> Option<Addrs> {
+        L::addrs(stream, addr)
+    }
+}
+
+fn socket(addr: SocketAddr, ty: Type, ipv6_only: Option<bool>) -> Result<Socket, io::Error> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    if let (SocketAddr::V6(_), Some(ipv6_only)) = (addr, ipv6_only) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Binds a TCP socket like `TcpListener::bind`, but with control over whether IPv6 sockets also accept IPv4.
+pub fn bind_tcp(addr: SocketAddr, ipv6_only: Option<bool>) -> Result<TcpListener, io::Error> {
+    let socket = socket(addr, Type::STREAM, ipv6_only)?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds a UDP socket like `UdpSocket::bind`, but with control over whether IPv6 sockets also receive IPv4.
+pub fn bind_udp(addr: SocketAddr, ipv6_only: Option<bool>) -> Result<UdpSocket, io::Error> {
+    let socket = socket(addr, Type::DGRAM, ipv6_only)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Binds a Unix socket, replacing any socket left behind by a previous run.
+/// A socket which another process is still listening on is left alone.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> Result<tokio::net::UnixListener, io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another process", path.display()),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        _ => {}
+    }
+    tokio::net::UnixListener::bind(path)
+}
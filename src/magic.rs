@@ -1,9 +1,47 @@
 use crate::config::HANDSHAKE_TIMEOUT;
+use crate::request::DEFAULT_SERVICE;
+use crate::wire;
+use std::convert::TryFrom;
 use std::io;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time::timeout;
 
 const MAGIC: [u8; 1] = [42];
+const MAGIC_HELLO: [u8; 1] = [43];
+
+/// How public connections are carried over gateway connections.
+#[derive(Copy, Clone)]
+pub enum Protocol {
+    /// Each gateway connection carries a single public connection.
+    Single,
+    /// One gateway connection carries many public connections, see `mux`.
+    Multiplexed,
+}
+
+/// Random identifier shared by all gateway connections from one client process.
+pub type ClientId = [u8; 16];
+
+pub fn random_id() -> Result<ClientId, io::Error> {
+    let mut id = [0; 16];
+    getrandom::getrandom(&mut id)?;
+    Ok(id)
+}
+
+/// Sent by the client in the early handshake.
+pub struct Hello {
+    pub protocol: Protocol,
+    pub id: ClientId,
+    /// Gateways from clients with higher priority are used first.
+    pub priority: i32,
+    /// Services the client can relay to.
+    pub services: Vec<String>,
+    /// Those of `services` whose private endpoint is UDP, so that they only receive from UDP public listeners.
+    pub udp: Vec<String>,
+    /// Older clients only send `MAGIC`, and don't expect a `request` before each connection.
+    /// Since they don't send an id either, each of their connections is given a new one.
+    /// This is ignored when writing, which always uses the current format.
+    pub legacy: bool,
+}
 
 pub async fn read_from(mut reader: impl AsyncRead + Unpin) -> Result<(), io::Error> {
     let mut buf = [0; 1];
@@ -15,5 +53,86 @@ pub async fn read_from(mut reader: impl AsyncRead + Unpin) -> Result<(), io::Err
 }
 
 pub async fn write_to(mut writer: impl AsyncWrite + Unpin) -> Result<(), io::Error> {
-    writer.write_all(&MAGIC).await
+    writer.write_all(&MAGIC).await?;
+    writer.flush().await
+}
+
+pub async fn read_hello(mut reader: impl AsyncRead + Unpin) -> Result<Hello, io::Error> {
+    let read = async {
+        let mut buf = [0; 1];
+        reader.read_exact(&mut buf).await?;
+        match buf {
+            MAGIC => {
+                return Ok(Hello {
+                    protocol: Protocol::Single,
+                    id: random_id()?,
+                    priority: 0,
+                    services: vec![DEFAULT_SERVICE.to_string()],
+                    udp: Vec::new(),
+                    legacy: true,
+                })
+            }
+            MAGIC_HELLO => {}
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        }
+        let protocol = match reader.read_u8().await? {
+            0 => Protocol::Single,
+            1 => Protocol::Multiplexed,
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        };
+        let mut id = [0; 16];
+        reader.read_exact(&mut id).await?;
+        let priority = reader.read_i32().await?;
+        let mut services = Vec::new();
+        let mut udp = Vec::new();
+        for _ in 0..reader.read_u8().await? {
+            let service = wire::read_str(&mut reader).await?;
+            match reader.read_u8().await? {
+                0 => {}
+                1 => udp.push(service.clone()),
+                _ => return Err(io::ErrorKind::InvalidData.into()),
+            }
+            services.push(service);
+        }
+        if services.is_empty() {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        Ok(Hello {
+            protocol,
+            id,
+            priority,
+            services,
+            udp,
+            legacy: false,
+        })
+    };
+    timeout(HANDSHAKE_TIMEOUT, read).await?
+}
+
+pub async fn write_hello(
+    mut writer: impl AsyncWrite + Unpin,
+    hello: &Hello,
+) -> Result<(), io::Error> {
+    let mut buf = MAGIC_HELLO.to_vec();
+    buf.push(match hello.protocol {
+        Protocol::Single => 0,
+        Protocol::Multiplexed => 1,
+    });
+    buf.extend_from_slice(&hello.id);
+    buf.extend_from_slice(&hello.priority.to_be_bytes());
+    match u8::try_from(hello.services.len()) {
+        Ok(len) => buf.push(len),
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too many services to send",
+            ))
+        }
+    }
+    for service in &hello.services {
+        wire::put_str(&mut buf, service)?;
+        buf.push(u8::from(hello.udp.contains(service)));
+    }
+    writer.write_all(&buf).await?;
+    writer.flush().await
 }
@@ -1,20 +1,48 @@
 #![allow(clippy::manual_map)]
 
+use std::cell::RefCell;
+
+mod activation;
+mod allow;
+mod auth;
 mod backoff;
 mod client;
 mod config;
+mod datagram;
+mod endpoint;
 mod err;
+mod file;
 mod future;
 mod heartbeat;
+mod http;
+mod listener;
 mod magic;
+mod mux;
 mod opt;
+mod pool;
+mod proxy;
+mod reload;
+mod request;
+mod resolve;
+mod route;
 mod rw;
 mod server;
-mod stream;
+mod sni;
+mod socks;
+mod tls;
+mod transport;
+mod tunnel;
+mod wire;
+mod ws;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), err::DebugFromDisplay<std::io::Error>> {
-    let opt::Options { verbose, mode } = clap::Parser::parse();
+    let opt::Options {
+        verbose,
+        mode,
+        config,
+        admin,
+    } = clap::Parser::parse();
 
     env_logger::Builder::new()
         .filter_level(match verbose {
@@ -25,18 +53,149 @@ async fn main() -> Result<(), err::DebugFromDisplay<std::io::Error>> {
         })
         .init();
 
+    if admin.is_some() && config.is_none() {
+        <opt::Options as clap::CommandFactory>::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--admin can only be used with --config",
+            )
+            .exit();
+    }
+
     let local = tokio::task::LocalSet::new();
+    let is_server = |mode: &opt::Mode| matches!(mode, opt::Mode::Server { .. });
+    match (mode, config) {
+        (Some(mode), None) => {
+            let servers = usize::from(is_server(&mode));
+            let inherited = RefCell::new(activation::Inherited::from_env(servers)?);
+            local.run_until(run(&local, mode, &inherited)).await?
+        }
+        (None, Some(path)) => {
+            let tunnels = file::load(&path)?;
+            let servers = tunnels.iter().filter(|t| is_server(&t.mode)).count();
+            let inherited = RefCell::new(activation::Inherited::from_env(servers)?);
+            let run = |mode| run(&local, mode, &inherited);
+            local
+                .run_until(reload::supervise(&path, tunnels, admin, run))
+                .await?
+        }
+        (Some(_), Some(_)) => <opt::Options as clap::CommandFactory>::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--config can't be used with a subcommand",
+            )
+            .exit(),
+        (None, None) => unreachable!("--config is required without a subcommand"),
+    }
+
+    Ok(())
+}
 
+async fn run(
+    local: &tokio::task::LocalSet,
+    mode: opt::Mode,
+    inherited: &RefCell<activation::Inherited>,
+) -> Result<(), std::io::Error> {
     match mode {
-        opt::Mode::Server { gateway, public } => {
-            local
-                .run_until(server::run(&local, &gateway, &public))
-                .await?;
+        opt::Mode::Server {
+            gateway,
+            public,
+            route,
+            unknown_host_status,
+            ipv6_only,
+            accept_proxy_protocol,
+            pool_size,
+            queue_timeout,
+            balance,
+            secret,
+            websocket,
+            tls,
+        } => {
+            let secret = secret.load()?;
+            let tls = tls.load()?;
+            server::run(
+                local,
+                &server::Bind {
+                    gateway,
+                    ipv6_only,
+                    inherited,
+                },
+                server::Public {
+                    listeners: public,
+                    routes: route::Routes::new(route),
+                    unknown_host: unknown_host_status,
+                    accept_proxy: accept_proxy_protocol,
+                    queue_timeout: std::time::Duration::from_secs(queue_timeout),
+                },
+                secret,
+                server::Link { tls, websocket },
+                pool_size.get(),
+                balance,
+            )
+            .await?;
         }
-        opt::Mode::Client { gateway, private } => {
-            local
-                .run_until(client::run(&local, &gateway, &private))
-                .await;
+        opt::Mode::Client {
+            gateway,
+            private,
+            pool_size,
+            all_gateways,
+            dns_ttl,
+            max_backoff,
+            mux,
+            priority,
+            socks,
+            socks_allow,
+            proxy_protocol,
+            secret,
+            proxy,
+            websocket,
+            tls,
+        } => {
+            let secret = secret.load()?;
+            let tls = tls.load(&gateway)?;
+            let dns_ttl = dns_ttl.map(std::time::Duration::from_secs);
+            let gateway = resolve::Resolver::new(gateway, dns_ttl);
+            // treat each address as a separate server, or all of them as one server reachable at any of them
+            let servers = match all_gateways {
+                true => client::Servers::Each(gateway),
+                false => client::Servers::Any(gateway),
+            };
+            let services = client::services(private, socks, socks_allow, proxy_protocol, dns_ttl)?;
+            let proxy = proxy.map(|proxy| tunnel::Proxy {
+                kind: proxy.kind,
+                addr: resolve::Resolver::new(proxy.addr, dns_ttl),
+                credentials: proxy.credentials,
+            });
+            let hello = magic::Hello {
+                protocol: match mux {
+                    true => magic::Protocol::Multiplexed,
+                    false => magic::Protocol::Single,
+                },
+                id: magic::random_id()?,
+                priority,
+                services: services.keys().cloned().collect(),
+                udp: services
+                    .iter()
+                    .filter(|(_, service)| service.is_udp())
+                    .map(|(name, _)| name.clone())
+                    .collect(),
+                legacy: false,
+            };
+            client::run(
+                local,
+                servers,
+                services,
+                secret,
+                client::Link {
+                    max_backoff,
+                    proxy,
+                    tls,
+                    websocket,
+                },
+                hello,
+                pool_size.get(),
+            )
+            .await;
         }
     }
 
@@ -0,0 +1,721 @@
+//! Multiplexes many logical streams over one gateway connection.
+//!
+//! Every frame starts with a 10-byte header: type (1), flags (1), stream id (4), length (4).
+//! For `DATA`, length is the size of the payload that follows;
+//! for `WINDOW_UPDATE`, it is the number of bytes the receiver has consumed.
+//! Streams are opened with `SYN`, half-closed with `FIN`, and aborted with `RST`.
+//! Each side sends `PING` periodically, so that a dead connection is noticed.
+//! A side that stops using the session sends `GOAWAY`, after which neither side opens new streams on it.
+
+use crate::config::{HEARTBEAT_TIMEOUT, MUX_MAX_FRAME_SIZE, MUX_MAX_OUTGOING, MUX_WINDOW_SIZE};
+use crate::transport::Gateway;
+use futures::future;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::{Infallible, TryInto};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::time::{interval, timeout};
+
+const DATA: u8 = 0;
+const WINDOW_UPDATE: u8 = 1;
+const PING: u8 = 2;
+const GOAWAY: u8 = 3;
+
+const SYN: u8 = 1 << 0;
+const FIN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+
+const HEADER_LEN: usize = 10;
+
+fn frame(kind: u8, flags: u8, id: u32, len: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "Session closed")
+}
+
+fn going_away() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "Session going away")
+}
+
+fn drained() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "Session dropped")
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn stream_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Stream closed")
+}
+
+/// Which side of the session this is. Each side allocates stream ids of a different parity.
+pub enum Role {
+    Server,
+    Client,
+}
+
+pub struct Session {
+    shared: Rc<RefCell<Shared>>,
+}
+
+struct Shared {
+    streams: HashMap<u32, StreamState>,
+    next_id: u32,
+    accepted: VecDeque<u32>,
+    accept_waker: Option<Waker>,
+    outgoing: VecDeque<Vec<u8>>,
+    /// Total size of the frames in `outgoing`.
+    outgoing_len: usize,
+    /// Frames without a payload which don't belong to a stream's data, like pings and window updates,
+    /// sent ahead of `outgoing` so that they aren't delayed by a backlog of data.
+    control: VecDeque<Vec<u8>>,
+    writer_waker: Option<Waker>,
+    closed: bool,
+    /// The session was dropped, so no more streams will be opened or accepted,
+    /// and the connection closes once the existing ones finish.
+    draining: bool,
+    /// The peer sent `GOAWAY`, so no more streams will be opened or accepted.
+    going_away: bool,
+}
+
+struct StreamState {
+    received: VecDeque<u8>,
+    received_fin: bool,
+    recv_window: u32,
+    unacked: u32,
+    send_window: u32,
+    sent_fin: bool,
+    reset: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            received: VecDeque::new(),
+            received_fin: false,
+            recv_window: MUX_WINDOW_SIZE,
+            unacked: 0,
+            send_window: MUX_WINDOW_SIZE,
+            sent_fin: false,
+            reset: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+    }
+}
+
+impl Shared {
+    fn send(&mut self, frame: Vec<u8>) {
+        self.outgoing_len += frame.len();
+        self.outgoing.push_back(frame);
+        wake(&mut self.writer_waker);
+    }
+
+    fn send_control(&mut self, frame: Vec<u8>) {
+        self.control.push_back(frame);
+        wake(&mut self.writer_waker);
+    }
+
+    fn next_outgoing(&mut self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+        let frame = self.outgoing.pop_front()?;
+        let was_full = self.outgoing_len >= MUX_MAX_OUTGOING;
+        self.outgoing_len -= frame.len();
+        if was_full && self.outgoing_len < MUX_MAX_OUTGOING {
+            for stream in self.streams.values_mut() {
+                wake(&mut stream.write_waker);
+            }
+        }
+        Some(frame)
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        wake(&mut self.accept_waker);
+        wake(&mut self.writer_waker);
+        self.streams.values_mut().for_each(StreamState::wake);
+    }
+
+    fn go_away(&mut self) {
+        self.going_away = true;
+        wake(&mut self.accept_waker);
+    }
+
+    fn receive(&mut self, flags: u8, id: u32, payload: Vec<u8>) -> Result<(), io::Error> {
+        if flags & SYN != 0 {
+            if id % 2 == self.next_id % 2 || self.streams.contains_key(&id) {
+                return Err(invalid("Invalid stream id"));
+            }
+            if self.draining {
+                self.send_control(frame(DATA, RST, id, 0, &[]));
+                return Ok(());
+            }
+            self.streams.insert(id, StreamState::new());
+            self.accepted.push_back(id);
+            wake(&mut self.accept_waker);
+        }
+
+        // frames for streams which have already been dropped locally are ignored
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        if flags & RST != 0 {
+            stream.reset = true;
+            stream.wake();
+            return Ok(());
+        }
+        if stream.received_fin {
+            return Err(invalid("Data after FIN"));
+        }
+
+        let len = payload.len() as u32;
+        if len > stream.recv_window {
+            return Err(invalid("Flow control window exceeded"));
+        }
+        stream.recv_window -= len;
+        stream.received.extend(payload);
+        if flags & FIN != 0 {
+            stream.received_fin = true;
+        }
+        wake(&mut stream.read_waker);
+        Ok(())
+    }
+
+    fn credit(&mut self, id: u32, len: u32) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.send_window = stream.send_window.saturating_add(len);
+            wake(&mut stream.write_waker);
+        }
+    }
+}
+
+impl Session {
+    /// Returns the session, and a future which must be polled to drive it.
+    /// The future completes with the error that ended the session.
+    pub fn new(gateway: Gateway, role: Role) -> (Self, impl Future<Output = io::Error>) {
+        let shared = Rc::new(RefCell::new(Shared {
+            streams: HashMap::new(),
+            next_id: match role {
+                Role::Server => 1,
+                Role::Client => 2,
+            },
+            accepted: VecDeque::new(),
+            accept_waker: None,
+            outgoing: VecDeque::new(),
+            outgoing_len: 0,
+            control: VecDeque::new(),
+            writer_waker: None,
+            closed: false,
+            draining: false,
+            going_away: false,
+        }));
+        let driver = drive(gateway, shared.clone());
+        (Self { shared }, driver)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.borrow().closed
+    }
+
+    /// Whether the peer has stopped using the session, though its existing streams may still be open.
+    pub fn is_going_away(&self) -> bool {
+        self.shared.borrow().going_away
+    }
+
+    pub fn open(&self) -> Result<Stream, io::Error> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.closed {
+            return Err(closed());
+        }
+        if shared.going_away {
+            return Err(going_away());
+        }
+        let id = shared.next_id;
+        shared.next_id = match id.checked_add(2) {
+            Some(next_id) => next_id,
+            None => return Err(invalid("Stream ids exhausted")),
+        };
+        shared.streams.insert(id, StreamState::new());
+        shared.send(frame(DATA, SYN, id, 0, &[]));
+        Ok(Stream {
+            id,
+            shared: self.shared.clone(),
+        })
+    }
+
+    pub async fn accept(&self) -> Result<Stream, io::Error> {
+        future::poll_fn(|cx| {
+            let mut shared = self.shared.borrow_mut();
+            match shared.accepted.pop_front() {
+                Some(id) => Poll::Ready(Ok(Stream {
+                    id,
+                    shared: self.shared.clone(),
+                })),
+                None if shared.closed => Poll::Ready(Err(closed())),
+                None if shared.going_away => Poll::Ready(Err(going_away())),
+                None => {
+                    shared.accept_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.draining = true;
+        if !shared.closed {
+            shared.send_control(frame(GOAWAY, 0, 0, 0, &[]));
+        }
+        // streams that were never accepted won't be finished by anyone
+        while let Some(id) = shared.accepted.pop_front() {
+            shared.streams.remove(&id);
+            if !shared.closed {
+                shared.send(frame(DATA, RST, id, 0, &[]));
+            }
+        }
+        wake(&mut shared.writer_waker);
+    }
+}
+
+async fn drive(gateway: Gateway, shared: Rc<RefCell<Shared>>) -> io::Error {
+    let (reader, writer) = tokio::io::split(gateway);
+    let done = future::try_join3(
+        read_frames(reader, &shared),
+        write_frames(writer, &shared),
+        ping_forever(&shared),
+    )
+    .await;
+    shared.borrow_mut().close();
+    match done {
+        Ok((i, _, _)) => match i {},
+        Err(e) => e,
+    }
+}
+
+struct Header {
+    kind: u8,
+    flags: u8,
+    id: u32,
+    len: u32,
+}
+
+async fn read_frame(reader: &mut ReadHalf<Gateway>) -> Result<(Header, Vec<u8>), io::Error> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let header = Header {
+        kind: header[0],
+        flags: header[1],
+        id: u32::from_be_bytes(header[2..6].try_into().unwrap()),
+        len: u32::from_be_bytes(header[6..10].try_into().unwrap()),
+    };
+    let payload = match header.kind {
+        DATA if header.len > MUX_MAX_FRAME_SIZE => return Err(invalid("Frame too large")),
+        DATA => {
+            let mut payload = vec![0; header.len as usize];
+            reader.read_exact(&mut payload).await?;
+            payload
+        }
+        WINDOW_UPDATE | PING | GOAWAY => Vec::new(),
+        _ => return Err(invalid("Unknown frame type")),
+    };
+    Ok((header, payload))
+}
+
+async fn read_frames(
+    mut reader: ReadHalf<Gateway>,
+    shared: &RefCell<Shared>,
+) -> Result<Infallible, io::Error> {
+    loop {
+        // the peer pings regularly, so a long silence means the connection is gone
+        let (header, payload) = timeout(HEARTBEAT_TIMEOUT, read_frame(&mut reader)).await??;
+        match header.kind {
+            DATA => shared
+                .borrow_mut()
+                .receive(header.flags, header.id, payload)?,
+            WINDOW_UPDATE => shared.borrow_mut().credit(header.id, header.len),
+            GOAWAY => shared.borrow_mut().go_away(),
+            _ => {}
+        }
+        // control frames go out before any data, so a long backlog of them means the peer isn't reading,
+        // while provoking more of them, like resets
+        if shared.borrow().control.len() * HEADER_LEN > MUX_MAX_OUTGOING {
+            return Err(invalid("Peer isn't reading"));
+        }
+    }
+}
+
+async fn write_frames(
+    mut writer: WriteHalf<Gateway>,
+    shared: &RefCell<Shared>,
+) -> Result<Infallible, io::Error> {
+    loop {
+        let frame = future::poll_fn(|cx| {
+            let mut shared = shared.borrow_mut();
+            match shared.next_outgoing() {
+                Some(frame) => Poll::Ready(Ok(frame)),
+                None if shared.closed => Poll::Ready(Err(closed())),
+                None if shared.draining && shared.streams.is_empty() => Poll::Ready(Err(drained())),
+                None => {
+                    shared.writer_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await?;
+        writer.write_all(&frame).await?;
+        if shared.borrow().control.is_empty() && shared.borrow().outgoing.is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+async fn ping_forever(shared: &RefCell<Shared>) -> Result<Infallible, io::Error> {
+    let mut ping = interval(HEARTBEAT_TIMEOUT / 2);
+    loop {
+        ping.tick().await;
+        shared.borrow_mut().send_control(frame(PING, 0, 0, 0, &[]));
+    }
+}
+
+/// A logical stream within a session.
+pub struct Stream {
+    id: u32,
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.borrow_mut();
+        let shared = &mut *shared;
+        let stream = match shared.streams.get_mut(&self.id) {
+            Some(stream) => stream,
+            None => return Poll::Ready(Err(stream_closed())),
+        };
+
+        if !stream.received.is_empty() {
+            let (front, _) = stream.received.as_slices();
+            let n = front.len().min(buf.remaining());
+            buf.put_slice(&front[..n]);
+            stream.received.drain(..n);
+
+            // return credit to the sender once half of the window has been consumed
+            stream.unacked += n as u32;
+            if stream.unacked >= MUX_WINDOW_SIZE / 2 {
+                let credit = std::mem::take(&mut stream.unacked);
+                stream.recv_window += credit;
+                shared.send_control(frame(WINDOW_UPDATE, 0, self.id, credit, &[]));
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        if stream.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if stream.received_fin {
+            return Poll::Ready(Ok(()));
+        }
+        if shared.closed {
+            return Poll::Ready(Err(closed()));
+        }
+        stream.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut shared = self.shared.borrow_mut();
+        let shared = &mut *shared;
+        let full = shared.outgoing_len >= MUX_MAX_OUTGOING;
+        let stream = match shared.streams.get_mut(&self.id) {
+            Some(stream) => stream,
+            None => return Poll::Ready(Err(stream_closed())),
+        };
+
+        if stream.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if stream.sent_fin {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if shared.closed {
+            return Poll::Ready(Err(closed()));
+        }
+        if stream.send_window == 0 || full {
+            stream.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = (buf.len() as u32)
+            .min(stream.send_window)
+            .min(MUX_MAX_FRAME_SIZE);
+        stream.send_window -= len;
+        shared.send(frame(DATA, 0, self.id, len, &buf[..len as usize]));
+        Poll::Ready(Ok(len as usize))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let mut shared = self.shared.borrow_mut();
+        let stream = match shared.streams.get_mut(&self.id) {
+            Some(stream) => stream,
+            None => return Poll::Ready(Err(stream_closed())),
+        };
+        if !stream.sent_fin && !stream.reset {
+            stream.sent_fin = true;
+            shared.send(frame(DATA, FIN, self.id, 0, &[]));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        if let Some(stream) = shared.streams.remove(&self.id) {
+            let finished = stream.sent_fin && stream.received_fin;
+            if !finished && !stream.reset && !shared.closed {
+                shared.send(frame(DATA, RST, self.id, 0, &[]));
+            }
+            if shared.draining && shared.streams.is_empty() {
+                wake(&mut shared.writer_waker);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::{spawn_local, LocalSet};
+
+    fn pair() -> (Session, Session) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (client, client_driver) = Session::new(Box::new(a), Role::Client);
+        let (server, server_driver) = Session::new(Box::new(b), Role::Server);
+        spawn_local(client_driver);
+        spawn_local(server_driver);
+        (client, server)
+    }
+
+    fn data(flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        frame(DATA, flags, id, payload.len() as u32, payload)
+    }
+
+    /// Runs a server session which receives `frames` from the peer, returning the error that ends it.
+    async fn receive(frames: Vec<Vec<u8>>) -> io::Error {
+        let (a, mut peer) = tokio::io::duplex(64 * 1024);
+        let (_session, driver) = Session::new(Box::new(a), Role::Server);
+        let send = async move {
+            for frame in frames {
+                // the session stops reading once it fails
+                if peer.write_all(&frame).await.is_err() {
+                    break;
+                }
+            }
+            // keep the connection open, so that only an invalid frame ends the session
+            future::pending::<()>().await
+        };
+        tokio::select! {
+            e = driver => e,
+            () = send => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams() {
+        LocalSet::new()
+            .run_until(async {
+                let (client, server) = pair();
+                let mut opened = client.open().unwrap();
+                let mut accepted = server.accept().await.unwrap();
+                let mut reverse = server.open().unwrap();
+                let mut reverse_accepted = client.accept().await.unwrap();
+
+                // more than a window's worth, so it only gets through as the other side reads
+                let data = (0..3 * MUX_WINDOW_SIZE)
+                    .map(|i| i as u8)
+                    .collect::<Vec<_>>();
+                let send = async {
+                    opened.write_all(&data).await.unwrap();
+                    opened.shutdown().await.unwrap();
+                };
+                let mut received = Vec::new();
+                let (_, read) = tokio::join!(send, accepted.read_to_end(&mut received));
+                read.unwrap();
+                assert!(received == data);
+
+                accepted.write_all(b"reply").await.unwrap();
+                accepted.shutdown().await.unwrap();
+                let mut received = Vec::new();
+                opened.read_to_end(&mut received).await.unwrap();
+                assert_eq!(received, b"reply");
+
+                reverse.write_all(b"reverse").await.unwrap();
+                let mut buf = [0; 7];
+                reverse_accepted.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"reverse");
+
+                // a dropped stream is reset
+                drop(reverse_accepted);
+                let e = reverse.read(&mut buf).await.unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn invalid_frames() {
+        let too_large = frame(DATA, SYN, 2, MUX_MAX_FRAME_SIZE + 1, &[]);
+        let over_window = (0..=MUX_WINDOW_SIZE / MUX_MAX_FRAME_SIZE)
+            .map(|_| data(0, 2, &[0; MUX_MAX_FRAME_SIZE as usize]));
+        let cases = vec![
+            (vec![data(SYN, 1, &[])], "Invalid stream id"),
+            (
+                vec![data(SYN, 2, &[]), data(SYN, 2, &[])],
+                "Invalid stream id",
+            ),
+            (
+                vec![data(SYN, 2, &[]), data(FIN, 2, b"abc"), data(0, 2, b"x")],
+                "Data after FIN",
+            ),
+            (vec![too_large], "Frame too large"),
+            (
+                std::iter::once(data(SYN, 2, &[]))
+                    .chain(over_window)
+                    .collect(),
+                "Flow control window exceeded",
+            ),
+            (vec![frame(9, 0, 0, 0, &[])], "Unknown frame type"),
+        ];
+        for (frames, msg) in cases {
+            let e = receive(frames).await;
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(e.to_string(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_layout() {
+        let (a, mut peer) = tokio::io::duplex(64 * 1024);
+        let (session, driver) = Session::new(Box::new(a), Role::Client);
+        let mut stream = session.open().unwrap();
+        let frames = async {
+            stream.write_all(b"hi").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut frames = Vec::new();
+            while frames.len() < 3 {
+                let mut header = [0; HEADER_LEN];
+                peer.read_exact(&mut header).await.unwrap();
+                let mut payload = vec![0; if header[0] == DATA { header[9] } else { 0 } as usize];
+                peer.read_exact(&mut payload).await.unwrap();
+                if header[0] != PING {
+                    frames.push([&header[..], &payload].concat());
+                }
+            }
+            frames
+        };
+        let frames = tokio::select! {
+            frames = frames => frames,
+            e = driver => panic!("{}", e),
+        };
+        assert_eq!(
+            frames,
+            [data(SYN, 2, &[]), data(0, 2, b"hi"), data(FIN, 2, &[]),]
+        );
+    }
+
+    #[tokio::test]
+    async fn control_frames_first() {
+        let (a, _peer) = tokio::io::duplex(1024);
+        let (session, _driver) = Session::new(Box::new(a), Role::Client);
+        let mut stream = session.open().unwrap();
+        stream.write_all(b"hi").await.unwrap();
+        let mut shared = session.shared.borrow_mut();
+        shared.send_control(frame(PING, 0, 0, 0, &[]));
+        let frames = std::iter::from_fn(|| shared.next_outgoing()).collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            [
+                frame(PING, 0, 0, 0, &[]),
+                data(SYN, 2, &[]),
+                data(0, 2, b"hi")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn go_away() {
+        LocalSet::new()
+            .run_until(async {
+                let (client, server) = pair();
+                let mut opened = client.open().unwrap();
+                opened.write_all(b"before").await.unwrap();
+                let mut accepted = server.accept().await.unwrap();
+                let mut buf = [0; 6];
+                accepted.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"before");
+
+                drop(client);
+                // frames are sent in order, so this arrives after the GOAWAY
+                opened.write_all(b"after!").await.unwrap();
+                accepted.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"after!");
+                assert!(server.is_going_away());
+                assert!(server.open().is_err());
+                assert!(server.accept().await.is_err());
+
+                // the existing stream still works both ways
+                accepted.write_all(b"reply!").await.unwrap();
+                opened.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"reply!");
+            })
+            .await;
+    }
+}
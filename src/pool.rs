@@ -0,0 +1,340 @@
+use crate::heartbeat;
+use crate::magic::{ClientId, Hello};
+use crate::mux;
+use crate::transport::Gateway;
+use futures::future::{select, Either};
+use pin_utils::pin_mut;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::rc::{Rc, Weak};
+use std::str::FromStr;
+use tokio::sync::{oneshot, Notify};
+
+/// How to pick among clients which all have a gateway available.
+#[derive(Copy, Clone, Debug)]
+pub enum Balance {
+    /// The client which was picked least recently.
+    RoundRobin,
+    /// The client with the fewest active connections, breaking ties by round-robin.
+    LeastActive,
+    /// Any client, uniformly at random.
+    Random,
+}
+
+impl FromStr for Balance {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-active" => Ok(Balance::LeastActive),
+            "random" => Ok(Balance::Random),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected round-robin, least-active, or random",
+            )),
+        }
+    }
+}
+
+/// A client process, shared by all of its gateways.
+pub struct Client {
+    /// Description for logging: certificate subject or address of its first gateway.
+    pub name: String,
+    /// Services announced by the client in its early handshake.
+    pub services: Vec<String>,
+    /// Those of `services` whose private endpoint is UDP.
+    udp: Vec<String>,
+    /// Clients with lower priority are only used when no higher-priority client has a gateway available.
+    pub priority: i32,
+    /// Whether the client predates named services, see `magic::Hello`.
+    pub legacy: bool,
+    /// Number of public connections currently relayed to this client.
+    pub active: Cell<usize>,
+    /// When this client was last picked, for round-robin.
+    last_used: Cell<u64>,
+}
+
+impl Client {
+    fn serves(&self, service: &str, datagrams: bool) -> bool {
+        self.services.iter().any(|s| s == service)
+            && self.udp.iter().any(|s| s == service) == datagrams
+    }
+}
+
+/// Gateways from connected clients which are ready to carry a public connection.
+pub struct Pool {
+    balance: Balance,
+    /// Clients by what authenticated them (certificate subject or IP address) and the id they sent.
+    clients: RefCell<HashMap<(String, ClientId), Weak<Client>>>,
+    idle: RefCell<VecDeque<Idle>>,
+    sessions: RefCell<Vec<(mux::Session, Rc<Client>)>>,
+    available: Notify,
+    picks: Cell<u64>,
+}
+
+struct Idle {
+    client: Rc<Client>,
+    take: oneshot::Sender<oneshot::Sender<Gateway>>,
+}
+
+pub enum Taken {
+    /// A stream opened on a multiplexed session, which is ready to use immediately.
+    Stream(mux::Stream),
+    /// An idle gateway, whose heartbeat has been interrupted but not finalized.
+    Idle(Gateway),
+}
+
+impl Pool {
+    pub fn new(balance: Balance) -> Self {
+        Self {
+            balance,
+            clients: Default::default(),
+            idle: Default::default(),
+            sessions: Default::default(),
+            available: Notify::new(),
+            picks: Cell::new(0),
+        }
+    }
+
+    /// Finds the client which sent `hello`, if it has other gateways, or starts tracking a new one.
+    ///
+    /// Ids are chosen by clients, so they're only trusted together with `identity`,
+    /// and a gateway which claims an existing client's id must announce the same services and priority.
+    pub fn register(
+        &self,
+        hello: Hello,
+        identity: String,
+        name: &str,
+    ) -> Result<Rc<Client>, io::Error> {
+        let mut clients = self.clients.borrow_mut();
+        let key = (identity, hello.id);
+        if let Some(client) = clients.get(&key).and_then(Weak::upgrade) {
+            if client.services != hello.services
+                || client.udp != hello.udp
+                || client.priority != hello.priority
+                || client.legacy != hello.legacy
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Client id already used by {} with different services or priority",
+                        client.name
+                    ),
+                ));
+            }
+            return Ok(client);
+        }
+        clients.retain(|_, client| client.strong_count() > 0);
+        let client = Rc::new(Client {
+            name: name.to_string(),
+            services: hello.services,
+            udp: hello.udp,
+            priority: hello.priority,
+            legacy: hello.legacy,
+            active: Cell::new(0),
+            last_used: Cell::new(0),
+        });
+        clients.insert(key, Rc::downgrade(&client));
+        Ok(client)
+    }
+
+    pub fn add_session(&self, session: mux::Session, client: Rc<Client>) {
+        self.sessions.borrow_mut().push((session, client));
+        self.available.notify_waiters();
+    }
+
+    /// Keeps an idle gateway alive with heartbeats, until it is taken or the connection drops.
+    pub async fn hold(&self, mut gateway: Gateway, client: Rc<Client>) {
+        loop {
+            let (take, taken) = oneshot::channel();
+            self.idle.borrow_mut().push_back(Idle {
+                client: client.clone(),
+                take,
+            });
+            self.available.notify_waiters();
+
+            // heartbeat: so the client can tell if the connection drops
+            let reply = {
+                let heartbeat = heartbeat::write_forever(&mut gateway);
+                pin_mut!(heartbeat);
+                match select(taken, heartbeat).await {
+                    Either::Left((Ok(reply), _)) => reply,
+                    Either::Left((Err(_), _)) => return,
+                    Either::Right((Ok(i), _)) => match i {},
+                    Either::Right((Err(e), _)) => {
+                        log::info!("Heartbeat failed for {}: {}", client.name, e);
+                        return;
+                    }
+                }
+            };
+
+            // the taker may have been cancelled (e.g. by its queue timeout) since it asked,
+            // in which case the gateway is still unused, so it goes back to being idle;
+            // heartbeats are single bytes, so interrupting one doesn't leave a partial write behind
+            gateway = match reply.send(gateway) {
+                Ok(()) => return,
+                Err(gateway) => gateway,
+            };
+        }
+    }
+
+    /// Waits for a gateway which serves `service`, from a UDP private endpoint if `datagrams`, to become available.
+    /// Once a client is picked, its multiplexed sessions are preferred, since opening a stream doesn't need a round trip.
+    pub async fn take(&self, service: &str, datagrams: bool) -> (Taken, Rc<Client>) {
+        loop {
+            // create this first, so that gateways added in the meantime aren't missed
+            let available = self.available.notified();
+
+            let client = match self.pick(service, datagrams) {
+                Some(client) => client,
+                None => {
+                    available.await;
+                    continue;
+                }
+            };
+
+            if let Some(stream) = self.open_stream(&client) {
+                return (Taken::Stream(stream), client);
+            }
+
+            let idle = {
+                let mut idle = self.idle.borrow_mut();
+                let i = idle
+                    .iter()
+                    .position(|idle| Rc::ptr_eq(&idle.client, &client));
+                i.and_then(|i| idle.remove(i))
+            };
+            if let Some(Idle { client, take }) = idle {
+                let (reply, gateway) = oneshot::channel();
+                // if either channel is closed, the gateway was dropped, so try the next one
+                if take.send(reply).is_err() {
+                    continue;
+                }
+                // `hold` only gives up the gateway if this receiver is still alive, and a
+                // timeout around `take` polls it before expiring, so a sent gateway isn't lost
+                if let Ok(gateway) = gateway.await {
+                    return (Taken::Idle(gateway), client);
+                }
+            }
+        }
+    }
+
+    /// Picks one of the highest-priority clients which serve `service` and have a gateway available.
+    fn pick(&self, service: &str, datagrams: bool) -> Option<Rc<Client>> {
+        let mut sessions = self.sessions.borrow_mut();
+        let mut idle = self.idle.borrow_mut();
+        sessions.retain(|(session, _)| !session.is_closed() && !session.is_going_away());
+        // gateways for other services may not be taken for a while, so clean up dropped ones here
+        idle.retain(|idle| !idle.take.is_closed());
+
+        let mut candidates = Vec::<Rc<Client>>::new();
+        let clients = sessions
+            .iter()
+            .map(|(_, client)| client)
+            .chain(idle.iter().map(|idle| &idle.client));
+        for client in clients {
+            if client.serves(service, datagrams)
+                && !candidates.iter().any(|c| Rc::ptr_eq(c, client))
+            {
+                candidates.push(client.clone());
+            }
+        }
+
+        // standby clients only get connections when all higher-priority clients are busy or gone
+        let priority = candidates.iter().map(|client| client.priority).max()?;
+        candidates.retain(|client| client.priority == priority);
+
+        let client = match self.balance {
+            Balance::RoundRobin => candidates
+                .into_iter()
+                .min_by_key(|client| client.last_used.get()),
+            Balance::LeastActive => candidates
+                .into_iter()
+                .min_by_key(|client| (client.active.get(), client.last_used.get())),
+            Balance::Random => {
+                let i = random_index(candidates.len());
+                Some(candidates.swap_remove(i))
+            }
+        }?;
+
+        let picks = self.picks.get() + 1;
+        self.picks.set(picks);
+        client.last_used.set(picks);
+        Some(client)
+    }
+
+    fn open_stream(&self, client: &Rc<Client>) -> Option<mux::Stream> {
+        let mut stream = None;
+        // sessions which fail to open a stream are unusable, so drop them
+        self.sessions.borrow_mut().retain(|(session, c)| {
+            if stream.is_some() || !Rc::ptr_eq(c, client) {
+                return true;
+            }
+            match session.open() {
+                Ok(s) => {
+                    stream = Some(s);
+                    true
+                }
+                Err(e) => {
+                    log::info!("Failed to open stream for {}: {}", client.name, e);
+                    false
+                }
+            }
+        });
+        stream
+    }
+}
+
+fn random_index(len: usize) -> usize {
+    let mut bytes = [0; 8];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => (u64::from_le_bytes(bytes) % len as u64) as usize,
+        Err(e) => {
+            log::warn!("Failed to generate random number: {}", e);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::Protocol;
+
+    fn hello(id: u8, services: &[&str], priority: i32) -> Hello {
+        Hello {
+            protocol: Protocol::Single,
+            id: [id; 16],
+            priority,
+            services: services.iter().map(|s| s.to_string()).collect(),
+            udp: Vec::new(),
+            legacy: false,
+        }
+    }
+
+    #[test]
+    fn register() {
+        let pool = Pool::new(Balance::RoundRobin);
+        let a = pool
+            .register(hello(1, &["a"], 0), "10.0.0.1".into(), "10.0.0.1:1")
+            .unwrap();
+        let same = pool
+            .register(hello(1, &["a"], 0), "10.0.0.1".into(), "10.0.0.1:2")
+            .unwrap();
+        assert!(Rc::ptr_eq(&a, &same));
+        assert_eq!(same.name, "10.0.0.1:1");
+
+        // the same id from elsewhere is a different client
+        let other = pool
+            .register(hello(1, &["a"], 0), "10.0.0.2".into(), "10.0.0.2:1")
+            .unwrap();
+        assert!(!Rc::ptr_eq(&a, &other));
+
+        let services = pool.register(hello(1, &["a", "b"], 0), "10.0.0.1".into(), "10.0.0.1:3");
+        assert!(services.is_err());
+        let priority = pool.register(hello(1, &["a"], 1), "10.0.0.1".into(), "10.0.0.1:4");
+        assert!(priority.is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! The PROXY protocol, which passes the addresses of a relayed connection to the server it's relayed to.
+//! See https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+use crate::config::HANDSHAKE_TIMEOUT;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+const V1_PREFIX: [u8; 6] = *b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Source and destination addresses of a public connection.
+#[derive(Copy, Clone, Debug)]
+pub struct Addrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Version {
+    /// Human-readable header.
+    V1,
+    /// Binary header.
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected v1 or v2",
+            )),
+        }
+    }
+}
+
+/// Builds the header to send before any other data, or an "unknown" header if the addresses aren't known.
+pub fn header(version: Version, addrs: Option<Addrs>) -> Vec<u8> {
+    // both addresses must be of the same family, so fall back to IPv4-mapped IPv6 addresses if they aren't
+    let addrs = addrs.map(
+        |Addrs {
+             source,
+             destination,
+         }| match (source, destination) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+                (source, destination)
+            }
+            _ => (to_ipv6(source), to_ipv6(destination)),
+        },
+    );
+
+    match (version, addrs) {
+        (Version::V1, Some((source, destination))) => {
+            let family = match source {
+                SocketAddr::V4(_) => "TCP4",
+                SocketAddr::V6(_) => "TCP6",
+            };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        (Version::V1, None) => b"PROXY UNKNOWN\r\n".to_vec(),
+        (Version::V2, Some((source, destination))) => {
+            let mut buf = V2_SIGNATURE.to_vec();
+            // version 2, PROXY command
+            buf.push(0x21);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    // AF_INET, STREAM
+                    buf.push(0x11);
+                    buf.extend_from_slice(&12u16.to_be_bytes());
+                    buf.extend_from_slice(&src.octets());
+                    buf.extend_from_slice(&dst.octets());
+                }
+                (src, dst) => {
+                    // AF_INET6, STREAM
+                    buf.push(0x21);
+                    buf.extend_from_slice(&36u16.to_be_bytes());
+                    buf.extend_from_slice(&octets_v6(src));
+                    buf.extend_from_slice(&octets_v6(dst));
+                }
+            }
+            buf.extend_from_slice(&source.port().to_be_bytes());
+            buf.extend_from_slice(&destination.port().to_be_bytes());
+            buf
+        }
+        (Version::V2, None) => {
+            let mut buf = V2_SIGNATURE.to_vec();
+            // version 2, LOCAL command, unspecified family, no addresses
+            buf.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+            buf
+        }
+    }
+}
+
+/// Reads and strips the header from the start of a connection.
+/// Returns the addresses it contains, or `None` if the connection's own addresses should be used.
+pub async fn read_header(mut reader: impl AsyncRead + Unpin) -> Result<Option<Addrs>, io::Error> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        // both signatures are at least this long, and this doesn't read past the end of the shortest v1 header
+        let mut start = [0; 6];
+        reader.read_exact(&mut start).await?;
+        if start == V1_PREFIX {
+            read_v1(&mut reader).await
+        } else if start == V2_SIGNATURE[..6] {
+            let mut rest = [0; 6];
+            reader.read_exact(&mut rest).await?;
+            if rest != V2_SIGNATURE[6..] {
+                return Err(invalid("Invalid PROXY v2 signature"));
+            }
+            read_v2(&mut reader).await
+        } else {
+            Err(invalid("Missing PROXY header"))
+        }
+    })
+    .await?
+}
+
+async fn read_v1(mut reader: impl AsyncRead + Unpin) -> Result<Option<Addrs>, io::Error> {
+    // read byte-by-byte, so as not to consume any data after the header
+    let mut line = V1_PREFIX.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line =
+        str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("Invalid PROXY v1 header"))?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let parse = |ip: &str, port: &str| -> Result<SocketAddr, io::Error> {
+                let ip = ip
+                    .parse::<IpAddr>()
+                    .map_err(|_| invalid("Invalid PROXY v1 address"))?;
+                if ip.is_ipv4() != (family == "TCP4") {
+                    return Err(invalid("PROXY v1 address doesn't match its family"));
+                }
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| invalid("Invalid PROXY v1 port"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(Some(Addrs {
+                source: parse(source, source_port)?,
+                destination: parse(destination, destination_port)?,
+            }))
+        }
+        _ => Err(invalid("Invalid PROXY v1 header")),
+    }
+}
+
+async fn read_v2(mut reader: impl AsyncRead + Unpin) -> Result<Option<Addrs>, io::Error> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header).await?;
+    let [version_command, family, len @ ..] = header;
+    let mut body = vec![0; usize::from(u16::from_be_bytes(len))];
+    reader.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY version"));
+    }
+    match version_command & 0xf {
+        // LOCAL: e.g. health checks from the proxy itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("Unsupported PROXY command")),
+    }
+    // the address family is the high nibble; the transport protocol (low nibble) doesn't matter here
+    let addrs = match (family >> 4, &body[..]) {
+        (1, [a, b, c, d, e, f, g, h, sp0, sp1, dp0, dp1, ..]) => Addrs {
+            source: SocketAddr::new(
+                Ipv4Addr::new(*a, *b, *c, *d).into(),
+                u16::from_be_bytes([*sp0, *sp1]),
+            ),
+            destination: SocketAddr::new(
+                Ipv4Addr::new(*e, *f, *g, *h).into(),
+                u16::from_be_bytes([*dp0, *dp1]),
+            ),
+        },
+        (2, body) if body.len() >= 36 => {
+            let ip = |octets: &[u8]| {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(octets);
+                IpAddr::from(Ipv6Addr::from(ip))
+            };
+            let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+            Addrs {
+                source: SocketAddr::new(ip(&body[0..16]), port(&body[32..34])),
+                destination: SocketAddr::new(ip(&body[16..32]), port(&body[34..36])),
+            }
+        }
+        // unspecified or Unix addresses, which can't be forwarded
+        (0, _) | (3, _) => return Ok(None),
+        _ => return Err(invalid("Invalid PROXY v2 addresses")),
+    };
+    Ok(Some(addrs))
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+fn octets_v6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> Addrs {
+        Addrs {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    /// Reads a header followed by other data, checking that the data isn't consumed.
+    async fn read(header: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>, io::Error> {
+        let data = [header, b"rest"].concat();
+        let mut reader = &data[..];
+        let addrs = read_header(&mut reader).await?;
+        assert_eq!(reader, b"rest");
+        Ok(addrs.map(|addrs| (addrs.source, addrs.destination)))
+    }
+
+    #[test]
+    fn v1_header() {
+        let header = |addrs| String::from_utf8(header(Version::V1, addrs)).unwrap();
+        assert_eq!(
+            header(Some(addrs("192.0.2.1:1234", "198.51.100.1:80"))),
+            "PROXY TCP4 192.0.2.1 198.51.100.1 1234 80\r\n"
+        );
+        assert_eq!(
+            header(Some(addrs("[2001:db8::1]:1234", "[2001:db8::2]:80"))),
+            "PROXY TCP6 2001:db8::1 2001:db8::2 1234 80\r\n"
+        );
+        assert_eq!(
+            header(Some(addrs("192.0.2.1:1234", "[2001:db8::2]:80"))),
+            "PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 1234 80\r\n"
+        );
+        assert_eq!(header(None), "PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_header() {
+        let header = header(
+            Version::V2,
+            Some(addrs("192.0.2.1:1234", "198.51.100.1:80")),
+        );
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        expected.extend_from_slice(&[0x04, 0xd2, 0, 80]);
+        assert_eq!(header, expected);
+    }
+
+    #[tokio::test]
+    async fn read_headers() {
+        for version in [Version::V1, Version::V2] {
+            for (source, destination) in [
+                ("192.0.2.1:1234", "198.51.100.1:80"),
+                ("[2001:db8::1]:1234", "[2001:db8::2]:80"),
+            ] {
+                let expected = addrs(source, destination);
+                let read = read(&header(version, Some(expected))).await.unwrap();
+                assert_eq!(read, Some((expected.source, expected.destination)));
+            }
+            assert_eq!(read(&header(version, None)).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_invalid_headers() {
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(200, b'1');
+        let mut unsupported_v2 = V2_SIGNATURE.to_vec();
+        unsupported_v2.extend_from_slice(&[0x13, 0x11, 0, 0]);
+        for header in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 192.0.2.1\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 1234 99999\r\n",
+            b"PROXY TCP4 example.com 198.51.100.1 1234 80\r\n",
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 1234 80\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.1 1234 80\r\n",
+            b"PROXY TCP6 2001:db8::1 198.51.100.1 1234 80\r\n",
+            b"\r\n\r\n\0\rnope\n",
+            &long,
+            &unsupported_v2,
+        ] {
+            let e = read(header).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", header);
+        }
+    }
+}
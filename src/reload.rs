@@ -0,0 +1,273 @@
+//! Runs the tunnels in a configuration file, starting and stopping them as the file changes.
+//!
+//! Stopping a tunnel closes its listeners and idle gateways, but connections it's already relaying are
+//! spawned separately, so they continue until they finish.
+
+use crate::config::HANDSHAKE_TIMEOUT;
+use crate::file::{self, Tunnel};
+use crate::opt::Mode;
+use futures::future::{self, LocalBoxFuture};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::task::Poll;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+/// Longest admin command accepted, including the newline.
+const MAX_COMMAND_LEN: u64 = 256;
+
+/// A tunnel that stopped by itself: its name, whether it was started with the process, and why.
+type Exited = (String, bool, Result<(), io::Error>);
+
+/// A command read by an admin connection: who sent it, the command, and where to send the reply.
+type Command = (
+    SocketAddr,
+    Result<String, io::Error>,
+    oneshot::Sender<String>,
+);
+
+struct Running<'a> {
+    name: String,
+    /// Whether it was started with the process.
+    initial: bool,
+    run: LocalBoxFuture<'a, Result<(), io::Error>>,
+}
+
+struct Tunnels<'a, F> {
+    run: F,
+    /// Running tunnels, by the args that identify them. Removing one drops it, which closes its listeners.
+    running: HashMap<Vec<String>, Running<'a>>,
+}
+
+impl<'a, F, Fut> Tunnels<'a, F>
+where
+    F: Fn(Mode) -> Fut,
+    Fut: Future<Output = Result<(), io::Error>> + 'a,
+{
+    /// Stops the running tunnels which aren't in `tunnels`, then starts the ones which aren't running yet,
+    /// so a changed tunnel has closed its listeners by the time its replacement binds them.
+    ///
+    /// Fails with the name and error of each tunnel which failed to start.
+    async fn apply(&mut self, tunnels: Vec<Tunnel>, initial: bool) -> Result<(), io::Error> {
+        let wanted = tunnels.iter().map(|t| &t.args).collect::<HashSet<_>>();
+        self.running.retain(|args, running| {
+            let keep = wanted.contains(args);
+            if !keep {
+                log::info!("Stopping {}", running.name);
+            }
+            keep
+        });
+
+        let mut failed = Vec::new();
+        for Tunnel { name, args, mode } in tunnels {
+            // unchanged tunnels keep running, even if they moved within the file
+            if let Some(running) = self.running.get_mut(&args) {
+                running.name = name;
+                continue;
+            }
+            if !initial {
+                log::info!("Starting {}", name);
+            }
+            let mut run: LocalBoxFuture<'a, _> = Box::pin((self.run)(mode));
+            // servers bind their listeners when they're first polled, so that's when they fail to start
+            match future::poll_fn(|cx| Poll::Ready(run.as_mut().poll(cx))).await {
+                Poll::Pending => {
+                    self.running.insert(args, Running { name, initial, run });
+                }
+                Poll::Ready(Ok(())) => log::info!("{} stopped", name),
+                Poll::Ready(Err(e)) => {
+                    failed.push(io::Error::new(e.kind(), format!("{}: {}", name, e)))
+                }
+            }
+        }
+        match failed.len() {
+            0 => Ok(()),
+            1 => Err(failed.remove(0)),
+            _ => {
+                let errors = failed.iter().map(ToString::to_string).collect::<Vec<_>>();
+                Err(io::Error::other(errors.join("; ")))
+            }
+        }
+    }
+
+    /// Waits for a tunnel to stop by itself, rather than by being removed.
+    async fn exited(&mut self) -> Exited {
+        // every tunnel is polled on each wakeup, which is cheap for the few in a file
+        future::poll_fn(|cx| {
+            let exited = self.running.iter_mut().find_map(|(args, running)| {
+                match running.run.as_mut().poll(cx) {
+                    Poll::Ready(result) => Some((args.clone(), result)),
+                    Poll::Pending => None,
+                }
+            });
+            match exited.and_then(|(args, result)| Some((self.running.remove(&args)?, result))) {
+                Some((running, result)) => Poll::Ready((running.name, running.initial, result)),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    async fn reload(&mut self, path: &Path) -> Result<(), io::Error> {
+        log::info!("Reloading {}", path.display());
+        let tunnels = match file::load(path) {
+            Ok(tunnels) => tunnels,
+            Err(e) => {
+                log::error!("Failed to reload, keeping the running tunnels: {}", e);
+                return Err(e);
+            }
+        };
+        match self.apply(tunnels, false).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                log::error!("Failed to start {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Runs `initial`, the tunnels loaded from the file at `path`, with `run`,
+/// re-reading it on SIGHUP, or on a `reload` command to `admin`.
+///
+/// Errors from the initial tunnels are returned, like those of a single tunnel would be.
+/// Later, a file that fails to load leaves the running tunnels as they were, tunnels which fail to start are
+/// reported to whoever asked for the reload, and tunnels which fail after that are only logged.
+pub async fn supervise<'a, F, Fut>(
+    path: &Path,
+    initial: Vec<Tunnel>,
+    admin: Option<SocketAddr>,
+    run: F,
+) -> Result<(), io::Error>
+where
+    F: Fn(Mode) -> Fut,
+    Fut: Future<Output = Result<(), io::Error>> + 'a,
+{
+    let mut tunnels = Tunnels {
+        run,
+        running: HashMap::new(),
+    };
+    tunnels.apply(initial, true).await?;
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let admin = match admin {
+        Some(addr) => {
+            log::info!("Binding to admin: {}", addr);
+            Some(TcpListener::bind(addr).await?)
+        }
+        None => None,
+    };
+    // admin connections are handled in their own tasks, so a slow one can't hold up the tunnels
+    let (commands_tx, mut commands) = mpsc::unbounded_channel();
+
+    enum Event {
+        Exited(Exited),
+        #[cfg_attr(not(unix), allow(dead_code))]
+        Hangup,
+        Admin(Result<(TcpStream, SocketAddr), io::Error>),
+        Command(Command),
+    }
+
+    loop {
+        let event = {
+            let mut events: Vec<LocalBoxFuture<'_, Event>> = Vec::new();
+            events.push(Box::pin(async { Event::Exited(tunnels.exited().await) }));
+            #[cfg(unix)]
+            events.push(Box::pin(async {
+                hangup.recv().await;
+                Event::Hangup
+            }));
+            if let Some(admin) = &admin {
+                events.push(Box::pin(async move { Event::Admin(admin.accept().await) }));
+                events.push(Box::pin(async {
+                    match commands.recv().await {
+                        Some(command) => Event::Command(command),
+                        // a sender is kept below
+                        None => future::pending().await,
+                    }
+                }));
+            }
+            let (event, _, _) = future::select_all(events).await;
+            event
+        };
+
+        match event {
+            Event::Exited((name, initial, result)) => match result {
+                Ok(()) => log::info!("{} stopped", name),
+                Err(e) if initial => {
+                    return Err(io::Error::new(e.kind(), format!("{}: {}", name, e)))
+                }
+                Err(e) => log::error!("{} failed: {}", name, e),
+            },
+            Event::Hangup => {
+                log::info!("Received SIGHUP");
+                // failures are logged, and there's no one else to tell
+                let _ = tunnels.reload(path).await;
+            }
+            Event::Admin(Err(e)) => log::warn!("Failed to accept admin connection: {}", e),
+            Event::Admin(Ok((stream, addr))) => {
+                tokio::task::spawn_local(admin_connection(stream, addr, commands_tx.clone()));
+            }
+            Event::Command((addr, command, reply)) => {
+                let reply_text = match command.as_deref() {
+                    Ok("reload") => {
+                        log::info!("Received reload from {}", addr);
+                        match tunnels.reload(path).await {
+                            Ok(()) => "ok\n".to_string(),
+                            Err(e) => format!("error: {}\n", e),
+                        }
+                    }
+                    Ok(command) => {
+                        format!("error: unknown command `{}`, expected reload\n", command)
+                    }
+                    Err(e) => format!("error: {}\n", e),
+                };
+                // the connection may have given up already
+                let _ = reply.send(reply_text);
+            }
+        }
+    }
+}
+
+/// Reads a command from an admin connection, passes it to the supervisor, and writes back its reply.
+async fn admin_connection(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    commands: mpsc::UnboundedSender<Command>,
+) {
+    let command = match timeout(HANDSHAKE_TIMEOUT, read_command(&mut stream)).await {
+        Ok(command) => command,
+        Err(e) => Err(e.into()),
+    };
+    let (reply_tx, reply) = oneshot::channel();
+    if commands.send((addr, command, reply_tx)).is_err() {
+        return;
+    }
+    let reply = match reply.await {
+        Ok(reply) => reply,
+        Err(_) => return,
+    };
+    let written = match timeout(HANDSHAKE_TIMEOUT, stream.write_all(reply.as_bytes())).await {
+        Ok(written) => written,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = written {
+        log::info!("Failed to reply to admin command from {}: {}", addr, e);
+    }
+}
+
+async fn read_command(stream: &mut TcpStream) -> Result<String, io::Error> {
+    let mut line = Vec::new();
+    BufReader::new(stream.take(MAX_COMMAND_LEN))
+        .read_until(b'\n', &mut line)
+        .await?;
+    String::from_utf8(line)
+        .map(|line| line.trim().to_string())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Command is not UTF-8"))
+}
@@ -0,0 +1,121 @@
+use crate::config::{HANDSHAKE_TIMEOUT, TARGET_CONNECT_TIMEOUT};
+use crate::proxy::Addrs;
+use crate::wire;
+use std::fmt::{self, Display};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// Service used for unnamed public listeners and private addresses, and the only one legacy clients serve.
+pub const DEFAULT_SERVICE: &str = "default";
+
+// flags sent after the service name
+/// The public listener is UDP, see `Request::datagrams`.
+const DATAGRAMS: u8 = 1 << 0;
+/// The public connection's addresses follow.
+const HAS_ADDRS: u8 = 1 << 1;
+/// A SOCKS target follows, see `Request::target`.
+const HAS_TARGET: u8 = 1 << 2;
+
+/// Sent by the server before relaying each public connection, so the client knows where it's going.
+/// Legacy clients (see `magic::Hello`) don't receive this.
+pub struct Request {
+    pub service: String,
+    /// Addresses of the public connection, if it has any (i.e. it's not over a Unix socket).
+    pub addrs: Option<Addrs>,
+    /// Where a public SOCKS client asked to connect, see `socks`.
+    /// The client replies with `write_status` once it has tried to connect.
+    pub target: Option<Target>,
+    /// Whether this came from a UDP public listener, so the service's private endpoint must be UDP too.
+    pub datagrams: bool,
+}
+
+/// A host and port to connect to, where the host may be a name or an IP address.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+pub async fn read_from(mut reader: impl AsyncRead + Unpin) -> Result<Request, io::Error> {
+    let read = async {
+        let service = wire::read_str(&mut reader).await?;
+        let flags = reader.read_u8().await?;
+        if flags & !(DATAGRAMS | HAS_ADDRS | HAS_TARGET) != 0 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let addrs = match flags & HAS_ADDRS {
+            0 => None,
+            _ => Some(Addrs {
+                source: wire::read_addr(&mut reader).await?,
+                destination: wire::read_addr(&mut reader).await?,
+            }),
+        };
+        let target = match flags & HAS_TARGET {
+            0 => None,
+            _ => Some(Target {
+                host: wire::read_str(&mut reader).await?,
+                port: reader.read_u16().await?,
+            }),
+        };
+        Ok(Request {
+            service,
+            addrs,
+            target,
+            datagrams: flags & DATAGRAMS != 0,
+        })
+    };
+    timeout(HANDSHAKE_TIMEOUT, read).await?
+}
+
+pub async fn write_to(
+    mut writer: impl AsyncWrite + Unpin,
+    request: &Request,
+) -> Result<(), io::Error> {
+    let mut buf = Vec::new();
+    wire::put_str(&mut buf, &request.service)?;
+    let mut flags = 0;
+    if request.datagrams {
+        flags |= DATAGRAMS;
+    }
+    if request.addrs.is_some() {
+        flags |= HAS_ADDRS;
+    }
+    if request.target.is_some() {
+        flags |= HAS_TARGET;
+    }
+    buf.push(flags);
+    if let Some(addrs) = &request.addrs {
+        wire::put_addr(&mut buf, &addrs.source);
+        wire::put_addr(&mut buf, &addrs.destination);
+    }
+    if let Some(target) = &request.target {
+        wire::put_str(&mut buf, &target.host)?;
+        buf.extend_from_slice(&target.port.to_be_bytes());
+    }
+    writer.write_all(&buf).await?;
+    writer.flush().await
+}
+
+/// Sent by the client after a request with a target: a SOCKS reply code, see `socks::reply_code`.
+pub async fn write_status(
+    mut writer: impl AsyncWrite + Unpin,
+    status: u8,
+) -> Result<(), io::Error> {
+    writer.write_all(&[status]).await?;
+    writer.flush().await
+}
+
+/// Waits for the client to connect to a target, which may take a while.
+pub async fn read_status(mut reader: impl AsyncRead + Unpin) -> Result<u8, io::Error> {
+    timeout(TARGET_CONNECT_TIMEOUT, reader.read_u8()).await?
+}
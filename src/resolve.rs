@@ -0,0 +1,122 @@
+use crate::request::Target;
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+/// Checks that `arg` looks like `HOST:PORT`, without resolving it yet.
+pub fn validate(arg: &str) -> Result<String, io::Error> {
+    match arg.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(arg.to_string())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Expected HOST:PORT",
+        )),
+    }
+}
+
+/// The host part of `HOST:PORT`, without brackets around IPv6 addresses.
+pub fn host(host_port: &str) -> &str {
+    let host = match host_port.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => host_port,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Resolves a hostname each time it's used, or at most once per `ttl`, so that DNS changes are picked up.
+#[derive(Debug)]
+pub struct Resolver {
+    host: String,
+    ttl: Option<Duration>,
+    cache: RefCell<Cache>,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Option<Instant>,
+}
+
+impl Resolver {
+    pub fn new(host: String, ttl: Option<Duration>) -> Self {
+        Self {
+            host,
+            ttl,
+            cache: Default::default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// The unresolved host and port, for a proxy to resolve instead.
+    pub fn target(&self) -> Target {
+        let port = self
+            .host
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok());
+        Target {
+            host: host(&self.host).to_string(),
+            // `Resolver`s are only made from arguments which passed `validate`
+            port: port.unwrap_or_default(),
+        }
+    }
+
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, io::Error> {
+        {
+            let cache = self.cache.borrow();
+            if let (Some(ttl), Some(resolved_at)) = (self.ttl, cache.resolved_at) {
+                if resolved_at.elapsed() < ttl {
+                    return Ok(cache.addrs.clone());
+                }
+            }
+        }
+
+        let resolved = lookup_host(&self.host).await.and_then(|addrs| {
+            let addrs = addrs.collect::<Vec<_>>();
+            match addrs.len() {
+                0 => Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "Resolved to zero addresses",
+                )),
+                _ => Ok(addrs),
+            }
+        });
+
+        let mut cache = self.cache.borrow_mut();
+        let addrs = match resolved {
+            Ok(addrs) => addrs,
+            // a flaky resolver shouldn't take down gateways that were working
+            Err(e) if !cache.addrs.is_empty() => {
+                log::warn!(
+                    "Failed to resolve {}, using previous addresses: {}",
+                    self.host,
+                    e
+                );
+                return Ok(cache.addrs.clone());
+            }
+            Err(e) => return Err(e),
+        };
+        let changed = {
+            let (mut old, mut new) = (cache.addrs.clone(), addrs.clone());
+            old.sort();
+            new.sort();
+            old != new
+        };
+        if changed {
+            let list = addrs.iter().map(ToString::to_string).collect::<Vec<_>>();
+            match cache.resolved_at {
+                Some(_) => log::info!("{} now resolves to {}", self.host, list.join(", ")),
+                None => log::debug!("{} resolves to {}", self.host, list.join(", ")),
+            }
+        }
+        cache.addrs = addrs.clone();
+        cache.resolved_at = Some(Instant::now());
+        Ok(addrs)
+    }
+}
@@ -0,0 +1,68 @@
+/// Picks a service by hostname, for public listeners which route by TLS server name or HTTP Host header.
+#[derive(Default)]
+pub struct Routes {
+    routes: Vec<(String, String)>,
+}
+
+impl Routes {
+    /// Each route is a hostname, or `*.` followed by a domain to match all of its subdomains.
+    pub fn new(routes: Vec<(String, String)>) -> Self {
+        let routes = routes
+            .into_iter()
+            .map(|(host, service)| (normalize(&host), service))
+            .collect();
+        Self { routes }
+    }
+
+    pub fn services(&self) -> impl Iterator<Item = &String> {
+        self.routes.iter().map(|(_, service)| service)
+    }
+
+    /// Finds the service for `host`, preferring exact matches over wildcards.
+    pub fn lookup(&self, host: &str) -> Option<&str> {
+        let host = normalize(host);
+        let exact = self.routes.iter().find(|(route, _)| *route == host);
+        let wildcard = || {
+            self.routes
+                .iter()
+                .find(|(route, _)| match route.strip_prefix('*') {
+                    Some(domain) => host.ends_with(domain) && host.len() > domain.len(),
+                    None => false,
+                })
+        };
+        exact.or_else(wildcard).map(|(_, service)| service.as_str())
+    }
+}
+
+/// Hostnames are case-insensitive, and may be fully qualified with a trailing dot.
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let routes = Routes::new(
+            [
+                ("*.example.com", "wildcard"),
+                ("www.example.com.", "www"),
+                ("Other.Example", "other"),
+            ]
+            .iter()
+            .map(|(host, service)| (host.to_string(), service.to_string()))
+            .collect(),
+        );
+        assert_eq!(routes.lookup("www.example.com"), Some("www"));
+        assert_eq!(routes.lookup("WWW.Example.Com."), Some("www"));
+        assert_eq!(routes.lookup("api.example.com"), Some("wildcard"));
+        assert_eq!(routes.lookup("a.b.example.com"), Some("wildcard"));
+        assert_eq!(routes.lookup("other.example"), Some("other"));
+        // a wildcard matches subdomains, but not the domain itself
+        assert_eq!(routes.lookup("example.com"), None);
+        assert_eq!(routes.lookup("badexample.com"), None);
+        assert_eq!(routes.lookup("sub.other.example"), None);
+    }
+}
@@ -27,6 +27,7 @@ struct Buf {
     pos: usize,
     cap: usize,
     amt: u64,
+    needs_flush: bool,
     buf: Vec<u8>,
 }
 
@@ -43,6 +44,7 @@ impl Buf {
             pos: 0,
             cap: 0,
             amt: 0,
+            needs_flush: false,
             buf: vec![0; MIN_BUFFER_SIZE],
         }
     }
@@ -58,7 +60,14 @@ impl Buf {
                 BufState::ReadWrite => {
                     if self.pos == self.cap {
                         let mut buf = ReadBuf::new(&mut self.buf);
-                        ready!(Pin::new(&mut *reader).poll_read(cx, &mut buf))?;
+                        if Pin::new(&mut *reader).poll_read(cx, &mut buf)?.is_pending() {
+                            // flush before waiting for more data, in case the writer is buffered
+                            if self.needs_flush {
+                                ready!(Pin::new(&mut *writer).poll_flush(cx))?;
+                                self.needs_flush = false;
+                            }
+                            return Poll::Pending;
+                        }
                         if buf.filled().is_empty() {
                             self.state = BufState::Shutdown;
                         } else {
@@ -76,6 +85,7 @@ impl Buf {
                         } else {
                             self.pos += i;
                             self.amt += i as u64;
+                            self.needs_flush = true;
                             // if we read and write the full buffer at once, double it
                             if i == self.buf.len() && self.buf.len() < MAX_BUFFER_SIZE {
                                 let double_len = self.buf.len() * 2;
@@ -95,3 +105,55 @@ impl Buf {
         }
     }
 }
+
+/// A stream whose first bytes have already been read (e.g. to inspect them), which replays them before reading any more.
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.prefix.len() {
+            let len = buf.remaining().min(this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..][..len]);
+            this.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
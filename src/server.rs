@@ -1,35 +1,53 @@
+use crate::activation::Inherited;
+use crate::auth::{self, Secret};
 use crate::backoff::Backoff;
-use crate::config::{QUEUE_TIMEOUT, SERVER_ACCEPT_BACKOFF_SECS};
+use crate::config::{HEARTBEAT_TIMEOUT, SERVER_ACCEPT_BACKOFF_SECS, UDP_SESSION_QUEUE};
+use crate::datagram::{self, Activity, MAX_DATAGRAM_SIZE};
+use crate::endpoint::Endpoint;
 use crate::err::{AppliesTo, IoErrorExt};
 use crate::heartbeat;
-use crate::magic;
-use crate::rw::conjoin;
-use crate::stream::spawn_idle;
-use futures::future::{select, Either};
-use futures::stream;
-use futures::StreamExt;
+use crate::http;
+use crate::listener::{self, Listener};
+use crate::magic::{self, Hello, Protocol};
+use crate::mux;
+use crate::pool::{Balance, Client, Pool, Taken};
+use crate::proxy::{self, Addrs};
+use crate::request::{self, Request, Target};
+use crate::route::Routes;
+use crate::rw::{conjoin, Prefixed};
+use crate::sni;
+use crate::socks;
+use crate::tls;
+use crate::transport::Gateway;
+use crate::ws;
+use futures::future::{self, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
 use pin_utils::pin_mut;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering::*};
 use std::time::Duration;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::task::LocalSet;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, Notify, Semaphore};
+use tokio::task::{self, LocalSet};
 use tokio::time::error::Elapsed;
 use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsAcceptor;
 
-async fn accept(listener: &mut TcpListener) -> TcpStream {
+async fn accept<L: Listener>(listener: &mut L) -> (L::Stream, L::Addr) {
     let mut backoff = Backoff::new(SERVER_ACCEPT_BACKOFF_SECS);
     loop {
-        match listener.accept().await {
-            Ok((stream, _addr)) => {
+        match future::poll_fn(|cx| listener.poll_accept(cx)).await {
+            Ok((stream, addr)) => {
                 backoff.reset();
-                if let Err(e) = stream.set_nodelay(true) {
-                    log::warn!("Failed to set nodelay: {}", e);
-                    continue;
-                }
-                return stream;
+                return (stream, addr);
             }
             Err(e) => match e.applies_to() {
                 AppliesTo::Connection => log::info!("Aborted connection dropped: {}", e),
@@ -44,12 +62,33 @@ async fn accept(listener: &mut TcpListener) -> TcpStream {
     }
 }
 
-async fn drain_queue(listener: &mut TcpListener) {
+/// Like `accept`, but for a datagram on a UDP socket.
+async fn receive(socket: &UdpSocket, buf: &mut [u8]) -> (usize, SocketAddr) {
+    let mut backoff = Backoff::new(SERVER_ACCEPT_BACKOFF_SECS);
+    loop {
+        match socket.recv_from(buf).await {
+            Ok(received) => return received,
+            Err(e) => match e.applies_to() {
+                // errors from previous sends (e.g. ICMP port unreachable) may show up here, and don't affect other peers
+                AppliesTo::Connection => log::info!("Failed to receive datagram: {}", e),
+                AppliesTo::Listener => {
+                    log::error!("Error receiving datagrams: {}", e);
+                    let seconds = backoff.next();
+                    log::warn!("Retrying in {} seconds", seconds);
+                    sleep(Duration::from_secs(u64::from(seconds))).await;
+                }
+            },
+        }
+    }
+}
+
+async fn drain_queue(listener: &mut impl Listener) {
     loop {
         // timeout because we need to yield to receive the second queued conn
         // (listener.poll_recv() won't return Poll::Ready twice in a row,
         //  even if there are multiple queued connections)
-        match timeout(Duration::from_millis(1), listener.accept()).await {
+        let accept = future::poll_fn(|cx| listener.poll_accept(cx));
+        match timeout(Duration::from_millis(1), accept).await {
             Ok(Ok((_, _))) => log::info!("Queued conn dropped"),
             Ok(Err(e)) => match e.applies_to() {
                 AppliesTo::Connection => log::info!("Queued conn dropped: {}", e),
@@ -63,103 +102,679 @@ async fn drain_queue(listener: &mut TcpListener) {
     }
 }
 
-pub async fn run(
-    local: &LocalSet,
-    gateway_addr: &SocketAddr,
-    public_addr: &SocketAddr,
-) -> Result<(), io::Error> {
-    let active = Rc::new(AtomicUsize::new(0));
+/// How gateway connections are wrapped before the early handshake.
+pub struct Link {
+    pub tls: Option<TlsAcceptor>,
+    /// Expect an HTTP Upgrade to WebSocket, inside TLS if enabled, see `ws`.
+    pub websocket: bool,
+}
 
-    log::info!("Binding to gateway: {}", gateway_addr);
-    let gateway_connections = TcpListener::bind(gateway_addr).await?;
-    log::info!("Binding to public: {}", public_addr);
-    let mut public_connections = TcpListener::bind(public_addr).await?;
-
-    let gateway_connections = spawn_idle(local, |requests| {
-        stream::unfold(
-            (gateway_connections, requests),
-            |(mut gateway_connections, mut requests)| async {
-                loop {
-                    let mut gateway = accept(&mut gateway_connections).await;
-
-                    // early handshake: immediately kill unknown connections
-                    match magic::read_from(&mut gateway).await {
-                        Ok(()) => log::info!("Early handshake succeeded"),
-                        Err(e) => {
-                            log::info!("Early handshake failed: {}", e);
-                            continue;
-                        }
-                    }
-
-                    // heartbeat: so the client can tell if the connection drops
-                    let token = {
-                        let heartbeat = heartbeat::write_forever(&mut gateway);
-                        pin_mut!(heartbeat);
-                        match select(requests.next(), heartbeat).await {
-                            Either::Left((Some(token), _)) => token,
-                            Either::Left((None, _)) => return None,
-                            Either::Right((Ok(i), _)) => match i {},
-                            Either::Right((Err(e), _)) => {
-                                log::info!("Heartbeat failed: {}", e);
-                                continue;
-                            }
-                        }
-                    };
-
-                    return Some(((token, gateway), (gateway_connections, requests)));
-                }
-            },
-        )
-    });
+/// Returns the wrapped connection, along with the subject of the client's certificate, if it presented one.
+async fn secure(
+    gateway: TcpStream,
+    tls: Option<&TlsAcceptor>,
+) -> Result<(Gateway, Option<String>), io::Error> {
+    match tls {
+        Some(tls) => {
+            let gateway = tls::accept(tls, gateway).await?;
+            let subject = tls::client_subject(&gateway);
+            Ok((Box::new(gateway), subject))
+        }
+        None => Ok((Box::new(gateway), None)),
+    }
+}
+
+async fn upgrade(gateway: Gateway, websocket: bool) -> Result<Gateway, io::Error> {
+    match websocket {
+        true => Ok(Box::new(ws::accept(gateway).await?)),
+        false => Ok(gateway),
+    }
+}
+
+async fn early_handshake(
+    gateway: &mut Gateway,
+    secret: Option<&Secret>,
+) -> Result<Hello, io::Error> {
+    if let Some(secret) = secret {
+        auth::challenge(&mut *gateway, secret).await?;
+    }
+    magic::read_hello(gateway).await
+}
+
+async fn late_handshake(gateway: &mut Gateway, secret: Option<&Secret>) -> Result<(), io::Error> {
+    match secret {
+        Some(secret) => auth::challenge(gateway, secret).await,
+        None => magic::read_from(gateway).await,
+    }
+}
+
+struct Gateways {
+    /// Names of services with public listeners, other than UDP ones
+    services: Vec<String>,
+    /// Names of services with UDP public listeners
+    udp_services: Vec<String>,
+    secret: Option<Secret>,
+    link: Link,
+    pool: Pool,
+    /// Limits the number of idle gateways held in the pool
+    idle_limit: Semaphore,
+}
+
+/// Gateways are prepared and held within this future, so that they're closed when the server stops.
+async fn accept_gateways(mut listener: Vec<TcpListener>, gateways: &Gateways) -> Infallible {
+    let mut preparing = FuturesUnordered::new();
+    loop {
+        let (gateway, addr) = if preparing.is_empty() {
+            accept(&mut listener).await
+        } else {
+            let accepted = accept(&mut listener);
+            pin_mut!(accepted);
+            match future::select(accepted, preparing.next()).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(_) => continue,
+            }
+        };
+        preparing.push(prepare_gateway(gateway, addr, gateways));
+    }
+}
+
+async fn prepare_gateway(gateway: TcpStream, addr: SocketAddr, gateways: &Gateways) {
+    // tls: wrap the connection before anything else is exchanged
+    let (gateway, subject) = match secure(gateway, gateways.link.tls.as_ref()).await {
+        Ok(secured) => secured,
+        Err(e) => {
+            log::info!("TLS handshake failed: {}", e);
+            return;
+        }
+    };
+
+    // websocket: the connection may have passed through HTTP proxies, which need to see an upgrade
+    let mut gateway = match upgrade(gateway, gateways.link.websocket).await {
+        Ok(gateway) => gateway,
+        Err(e) => {
+            log::info!("WebSocket upgrade failed: {}", e);
+            return;
+        }
+    };
+
+    // the client is described by its certificate if it has one, and its address otherwise
+    let client = subject.clone().unwrap_or_else(|| addr.to_string());
+    let identity = subject.unwrap_or_else(|| addr.ip().to_string());
+
+    // early handshake: immediately kill unknown connections
+    let hello = match early_handshake(&mut gateway, gateways.secret.as_ref()).await {
+        Ok(hello) => {
+            log::info!("Early handshake succeeded");
+            hello
+        }
+        Err(e) => {
+            log::info!("Early handshake failed: {}", e);
+            return;
+        }
+    };
 
-    pin_mut!(gateway_connections);
+    // services: clients may only serve what the server has public listeners for, of the same kind
+    for service in &hello.services {
+        let (listeners, kind) = match hello.udp.contains(service) {
+            true => (&gateways.udp_services, "UDP "),
+            false => (&gateways.services, ""),
+        };
+        if !listeners.contains(service) {
+            log::warn!(
+                "Rejecting {}: no {}public listener for {}",
+                client,
+                kind,
+                service
+            );
+            return;
+        }
+    }
+    log::info!(
+        "Client {} serves {} with priority {}",
+        client,
+        hello.services.join(", "),
+        hello.priority
+    );
 
-    'public: loop {
-        let public = accept(&mut public_connections).await;
+    let protocol = hello.protocol;
+    let client = match gateways.pool.register(hello, identity, &client) {
+        Ok(client) => client,
+        Err(e) => {
+            log::warn!("Rejecting {}: {}", client, e);
+            return;
+        }
+    };
 
-        let gateway = loop {
-            // drop public connections which wait for too long, to avoid unlimited queuing when no gateway is connected
-            let mut gateway = match timeout(QUEUE_TIMEOUT, gateway_connections.next()).await {
-                Ok(Some(gateway)) => gateway,
-                Ok(None) => return Ok(()),
+    match protocol {
+        Protocol::Single => {
+            // if the pool stays full, drop the gateway before its heartbeat times out, so the client retries
+            let _permit = match timeout(HEARTBEAT_TIMEOUT / 2, gateways.idle_limit.acquire()).await
+            {
+                Ok(Ok(permit)) => permit,
+                Ok(Err(e)) => {
+                    log::warn!("Failed to reserve a pool slot for {}: {}", client.name, e);
+                    return;
+                }
                 Err(e) => {
                     let _: Elapsed = e;
-                    log::info!("Public connection expired waiting for gateway");
-                    drain_queue(&mut public_connections).await;
-                    continue 'public;
+                    log::info!("Pool full, dropping gateway from {}", client.name);
+                    return;
                 }
             };
+            gateways.pool.hold(gateway, client).await
+        }
+        // multiplexed: the client keeps this connection, and streams are opened on demand
+        Protocol::Multiplexed => {
+            log::info!("Multiplexed session started for {}", client.name);
+            let (session, driver) = mux::Session::new(gateway, mux::Role::Server);
+            gateways.pool.add_session(session, client.clone());
+            task::spawn_local(async move {
+                let e = driver.await;
+                log::info!("Multiplexed session closed for {}: {}", client.name, e);
+            });
+        }
+    }
+}
 
-            // finish heartbeat: do this as late as possible so clients can't send late handshake and disconnect
-            match heartbeat::write_final(&mut gateway).await {
-                Ok(()) => log::info!("Heartbeat completed"),
-                Err(e) => {
-                    log::info!("Heartbeat failed at finalization: {}", e);
-                    continue;
-                }
+/// How a public listener picks the service for each connection.
+#[derive(Copy, Clone, Debug)]
+pub enum Routing {
+    /// Always the listener's own service.
+    Fixed,
+    /// By the server name in the TLS ClientHello, see `sni`, falling back to the listener's own service.
+    Sni,
+    /// By the Host header of the first HTTP request, see `http`, responding with an error for unknown hosts.
+    Http,
+    /// Always the listener's own service, which connects to the target requested by the SOCKS client, see `socks`.
+    Socks,
+}
+
+#[derive(Clone, Debug)]
+pub struct PublicListener {
+    pub service: String,
+    pub routing: Routing,
+    pub endpoint: Endpoint<SocketAddr>,
+}
+
+/// Public listeners, and how connections to them are handled.
+pub struct Public {
+    pub listeners: Vec<PublicListener>,
+    /// Services for hostnames, used by listeners which don't have fixed routing.
+    pub routes: Routes,
+    /// Response to HTTP requests for hosts without a route.
+    pub unknown_host: http::Status,
+    /// Strip a PROXY protocol header from each stream connection, and use its addresses instead of the connection's.
+    pub accept_proxy: bool,
+    /// Drop public connections which wait this long for a gateway.
+    pub queue_timeout: Duration,
+}
+
+/// Where to receive gateway connections, and how to bind all listening sockets.
+pub struct Bind<'a> {
+    pub gateway: Vec<SocketAddr>,
+    /// Set IPV6_V6ONLY on IPv6 sockets, instead of using the system setting.
+    pub ipv6_only: Option<bool>,
+    /// Sockets from systemd, which are already bound, so they're used instead of binding the same address again.
+    pub inherited: &'a RefCell<Inherited>,
+}
+
+pub async fn run(
+    local: &LocalSet,
+    bind: &Bind<'_>,
+    public: Public,
+    secret: Option<Secret>,
+    link: Link,
+    pool_size: usize,
+    balance: Balance,
+) -> Result<(), io::Error> {
+    let active = Rc::new(AtomicUsize::new(0));
+    let public = Rc::new(public);
+    let gateways = Rc::new(Gateways {
+        services: public
+            .listeners
+            .iter()
+            // http: listeners only use the services they route to
+            .filter(|listener| !matches!(listener.routing, Routing::Http))
+            .filter(|listener| !matches!(listener.endpoint, Endpoint::Udp(_)))
+            .map(|listener| &listener.service)
+            .chain(public.routes.services())
+            .cloned()
+            .collect(),
+        udp_services: public
+            .listeners
+            .iter()
+            .filter(|listener| matches!(listener.endpoint, Endpoint::Udp(_)))
+            .map(|listener| listener.service.clone())
+            .collect(),
+        secret,
+        link,
+        pool: Pool::new(balance),
+        idle_limit: Semaphore::new(pool_size),
+    });
+
+    let mut gateway_connections = Vec::new();
+    for &addr in &bind.gateway {
+        log::info!("Binding to gateway: {}", addr);
+        gateway_connections.push(match bind.inherited.borrow_mut().tcp(addr)? {
+            Some(connections) => connections,
+            None => listener::bind_tcp(addr, bind.ipv6_only)?,
+        });
+    }
+    let mut listeners: Vec<Pin<Box<dyn Future<Output = Infallible>>>> = Vec::new();
+    for listener in &public.listeners {
+        let service = &listener.service;
+        log::info!("Binding to public for {}: {}", service, listener.endpoint);
+        match &listener.endpoint {
+            Endpoint::Tcp(addr) => {
+                let connections = match bind.inherited.borrow_mut().tcp(*addr)? {
+                    Some(connections) => connections,
+                    None => listener::bind_tcp(*addr, bind.ipv6_only)?,
+                };
+                listeners.push(Box::pin(serve(
+                    local,
+                    connections,
+                    Rc::new(listener.clone()),
+                    &public,
+                    &gateways,
+                    &active,
+                )));
+            }
+            Endpoint::Udp(addr) => {
+                let socket = match bind.inherited.borrow_mut().udp(*addr)? {
+                    Some(socket) => socket,
+                    None => listener::bind_udp(*addr, bind.ipv6_only)?,
+                };
+                listeners.push(Box::pin(serve_datagrams(
+                    local,
+                    socket,
+                    service,
+                    public.queue_timeout,
+                    &gateways,
+                    &active,
+                )));
             }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let connections = match bind.inherited.borrow_mut().unix(path)? {
+                    Some(connections) => connections,
+                    None => listener::bind_unix(path)?,
+                };
+                listeners.push(Box::pin(serve(
+                    local,
+                    connections,
+                    Rc::new(listener.clone()),
+                    &public,
+                    &gateways,
+                    &active,
+                )));
+            }
+        }
+    }
 
-            // late handshake: ensure that client hasn't disappeared some time after early handshake
-            match magic::read_from(&mut gateway).await {
-                Ok(()) => log::info!("Late handshake succeeded"),
-                Err(e) => {
-                    log::info!("Late handshake failed: {}", e);
+    bind.inherited.borrow_mut().bound();
+
+    listeners.push(Box::pin(accept_gateways(gateway_connections, &gateways)));
+
+    let (i, _, _) = future::select_all(listeners).await;
+    match i {}
+}
+
+/// Takes a gateway from the pool, and prepares it to relay a public connection.
+async fn open_gateway(gateways: &Gateways, request: &Request) -> (Gateway, Rc<Client>) {
+    loop {
+        let (mut gateway, client) = match gateways
+            .pool
+            .take(&request.service, request.datagrams)
+            .await
+        {
+            (Taken::Stream(mut stream), client) => {
+                log::info!("Opened multiplexed stream");
+                if let Err(e) = request::write_to(&mut stream, request).await {
+                    log::info!("Failed to send request: {}", e);
                     continue;
                 }
+                return (Box::new(stream), client);
+            }
+            (Taken::Idle(gateway), client) => (gateway, client),
+        };
+
+        // finish heartbeat: do this as late as possible so clients can't send late handshake and disconnect
+        match heartbeat::write_final(&mut gateway).await {
+            Ok(()) => log::info!("Heartbeat completed"),
+            Err(e) => {
+                log::info!("Heartbeat failed at finalization: {}", e);
+                continue;
+            }
+        }
+
+        // request: tell the client which service this is for, before it commits to the connection
+        if !client.legacy {
+            if let Err(e) = request::write_to(&mut gateway, request).await {
+                log::info!("Failed to send request: {}", e);
+                continue;
+            }
+        }
+
+        // late handshake: ensure that client hasn't disappeared some time after early handshake
+        match late_handshake(&mut gateway, gateways.secret.as_ref()).await {
+            Ok(()) => log::info!("Late handshake succeeded"),
+            Err(e) => {
+                log::info!("Late handshake failed: {}", e);
+                continue;
+            }
+        }
+
+        return (gateway, client);
+    }
+}
+
+/// Runs a relayed connection, keeping track of it for load balancing and logging.
+async fn relay(
+    relayed: impl Future<Output = Result<(u64, u64), io::Error>>,
+    service: String,
+    client: Rc<Client>,
+    active: Rc<AtomicUsize>,
+) {
+    client.active.set(client.active.get() + 1);
+    log::info!(
+        "Spawning {} ({} active) for {}",
+        service,
+        active.fetch_add(1, Relaxed) + 1,
+        client.name
+    );
+    let done = relayed.await;
+    client.active.set(client.active.get() - 1);
+    let active = active.fetch_sub(1, Relaxed) - 1;
+    match done {
+        Ok((down, up)) => {
+            log::info!(
+                "Closing {} ({} active) for {}: {}/{}",
+                service,
+                active,
+                client.name,
+                down,
+                up
+            )
+        }
+        Err(e) => log::info!(
+            "Closing {} ({} active) for {}: {}",
+            service,
+            active,
+            client.name,
+            e
+        ),
+    }
+}
+
+/// Waits for the client to connect to a SOCKS target, and tells the public SOCKS client how it went before relaying.
+async fn relay_target(
+    mut public: impl AsyncRead + AsyncWrite + Unpin,
+    mut gateway: Gateway,
+    target: Target,
+    service: String,
+    client: Rc<Client>,
+    active: Rc<AtomicUsize>,
+) {
+    let status = match request::read_status(&mut gateway).await {
+        Ok(status) => status,
+        Err(e) => {
+            log::info!("Failed to read status for {}: {}", target, e);
+            socks::REP_GENERAL_FAILURE
+        }
+    };
+    if let Err(e) = socks::reply(&mut public, status).await {
+        log::info!("Failed to send SOCKS reply: {}", e);
+        return;
+    }
+    if status != socks::REP_SUCCEEDED {
+        log::info!(
+            "Client {} failed to connect to {}: SOCKS error {}",
+            client.name,
+            target,
+            status
+        );
+        return;
+    }
+    relay(conjoin(public, gateway), service, client, active).await
+}
+
+/// Accepts public connections, each of which is routed and relayed in its own task,
+/// so that slow ones don't hold up the rest.
+async fn serve<L: Listener>(
+    local: &LocalSet,
+    mut public_connections: L,
+    listener: Rc<PublicListener>,
+    options: &Rc<Public>,
+    gateways: &Rc<Gateways>,
+    active: &Rc<AtomicUsize>,
+) -> Infallible {
+    // notified when a connection expires waiting for a gateway
+    let expired = Rc::new(Notify::new());
+    loop {
+        let accepted = {
+            let accepted = accept(&mut public_connections);
+            let expiry = expired.notified();
+            pin_mut!(accepted);
+            pin_mut!(expiry);
+            match future::select(accepted, expiry).await {
+                Either::Left((accepted, _)) => Some(accepted),
+                Either::Right(((), _)) => None,
+            }
+        };
+        let (public, addr) = match accepted {
+            Some(accepted) => accepted,
+            // no gateway is connected, so don't keep more connections waiting than necessary
+            None => {
+                drain_queue(&mut public_connections).await;
+                continue;
+            }
+        };
+        let addrs = L::addrs(&public, &addr);
+        local.spawn_local(serve_connection(
+            public,
+            addrs,
+            listener.clone(),
+            options.clone(),
+            gateways.clone(),
+            active.clone(),
+            expired.clone(),
+        ));
+    }
+}
+
+async fn serve_connection(
+    mut public: impl AsyncRead + AsyncWrite + Unpin,
+    mut addrs: Option<Addrs>,
+    listener: Rc<PublicListener>,
+    options: Rc<Public>,
+    gateways: Rc<Gateways>,
+    active: Rc<AtomicUsize>,
+    expired: Rc<Notify>,
+) {
+    // proxy: the connection is from a load balancer, which sends the real addresses first
+    if options.accept_proxy {
+        match proxy::read_header(&mut public).await {
+            Ok(Some(proxied)) => addrs = Some(proxied),
+            Ok(None) => {}
+            Err(e) => {
+                log::info!("Invalid PROXY header: {}", e);
+                return;
+            }
+        }
+    }
+    if let Some(addrs) = &addrs {
+        log::info!("Public connection from {}", addrs.source);
+    }
+
+    // routing: the service may depend on the start of the connection, which must be replayed afterwards
+    let mut peeked = Vec::new();
+    let mut target = None;
+    let routed = match listener.routing {
+        Routing::Fixed => Ok(None),
+        Routing::Sni => sni::read_server_name(&mut public, &mut peeked).await,
+        Routing::Http => http::read_host(&mut public, &mut peeked).await,
+        Routing::Socks => socks::accept(&mut public).await.map(|requested| {
+            log::info!("SOCKS connection to {}", requested);
+            target = Some(requested);
+            None
+        }),
+    };
+    let host = match routed {
+        Ok(host) => host,
+        Err(e) => {
+            log::info!("Failed to route public connection: {}", e);
+            return;
+        }
+    };
+    let route = host.as_deref().and_then(|host| options.routes.lookup(host));
+    let service = match (listener.routing, route) {
+        (_, Some(service)) => service,
+        (Routing::Http, None) => {
+            log::info!("No route for host {}", host.as_deref().unwrap_or("(none)"));
+            if let Err(e) = http::write_response(&mut public, options.unknown_host).await {
+                log::info!("Failed to send response: {}", e);
             }
+            return;
+        }
+        (_, None) => &listener.service,
+    };
+    if let Some(host) = &host {
+        log::info!("Routing {} to {}", host, service);
+    }
+    let public = Prefixed::new(peeked, public);
 
-            break gateway;
+    let request = Request {
+        service: service.to_string(),
+        addrs,
+        target,
+        datagrams: false,
+    };
+
+    // drop public connections which wait for too long, to avoid unlimited queuing when no gateway is connected
+    let (gateway, client) =
+        match timeout(options.queue_timeout, open_gateway(&gateways, &request)).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                let _: Elapsed = e;
+                log::info!("Public connection expired waiting for gateway");
+                expired.notify_one();
+                return;
+            }
         };
 
-        log::info!("Spawning ({} active)", active.fetch_add(1, Relaxed) + 1);
+    match request.target {
+        Some(target) => {
+            relay_target(public, gateway, target, request.service, client, active).await
+        }
+        None => relay(conjoin(public, gateway), request.service, client, active).await,
+    }
+}
+
+/// Relays datagrams from each source address over its own gateway, until no datagrams are relayed for a while.
+async fn serve_datagrams(
+    local: &LocalSet,
+    socket: UdpSocket,
+    service: &str,
+    queue_timeout: Duration,
+    gateways: &Rc<Gateways>,
+    active: &Rc<AtomicUsize>,
+) -> Infallible {
+    let local_addr = match socket.local_addr() {
+        Ok(local_addr) => Some(local_addr),
+        Err(e) => {
+            log::warn!("Failed to get local address: {}", e);
+            None
+        }
+    };
+    let socket = Rc::new(socket);
+    let sessions = Rc::new(RefCell::new(HashMap::new()));
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, peer) = receive(&socket, &mut buf).await;
+        let datagram = buf[..len].to_vec();
+
+        if let Some(session) = sessions.borrow().get(&peer) {
+            let session: &mpsc::Sender<Vec<u8>> = session;
+            // like any other congested link, drop datagrams when the gateway can't keep up
+            if session.try_send(datagram).is_err() {
+                log::debug!("Dropped datagram from {}", peer);
+            }
+            continue;
+        }
+
+        let (session, datagrams) = mpsc::channel(UDP_SESSION_QUEUE);
+        let _ = session.try_send(datagram);
+        sessions.borrow_mut().insert(peer, session);
+
+        let request = Request {
+            service: service.to_string(),
+            addrs: local_addr.map(|destination| Addrs {
+                source: peer,
+                destination,
+            }),
+            target: None,
+            datagrams: true,
+        };
+        let socket = socket.clone();
+        let sessions = sessions.clone();
+        let gateways = gateways.clone();
         let active = active.clone();
         local.spawn_local(async move {
-            let done = conjoin(public, gateway).await;
-            let active = active.fetch_sub(1, Relaxed) - 1;
-            match done {
-                Ok((down, up)) => log::info!("Closing ({} active): {}/{}", active, down, up),
-                Err(e) => log::info!("Closing ({} active): {}", active, e),
+            match timeout(queue_timeout, open_gateway(&gateways, &request)).await {
+                Ok((gateway, client)) => {
+                    let relayed = relay_datagrams(gateway, &socket, peer, datagrams);
+                    relay(relayed, request.service, client, active).await;
+                }
+                Err(e) => {
+                    let _: Elapsed = e;
+                    log::info!("Datagrams from {} expired waiting for gateway", peer);
+                }
             }
+            sessions.borrow_mut().remove(&peer);
         });
     }
 }
+
+async fn relay_datagrams(
+    gateway: Gateway,
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+) -> Result<(u64, u64), io::Error> {
+    let (mut reader, mut writer) = tokio::io::split(gateway);
+    let activity = Activity::new();
+    let (down, up) = (Cell::new(0), Cell::new(0));
+
+    let to_gateway = async {
+        while let Some(datagram) = datagrams.recv().await {
+            activity.touch();
+            down.set(down.get() + datagram.len() as u64);
+            datagram::write_to(&mut writer, &datagram).await?;
+        }
+        Ok(())
+    };
+    let from_gateway = async {
+        let mut buf = Box::new([0; MAX_DATAGRAM_SIZE]);
+        while let Some(len) = datagram::read_from(&mut reader, &mut buf).await? {
+            activity.touch();
+            up.set(up.get() + len as u64);
+            socket.send_to(&buf[..len], peer).await?;
+        }
+        Ok(())
+    };
+    let expired = async {
+        activity.expired().await;
+        Ok(())
+    };
+    pin_mut!(to_gateway);
+    pin_mut!(from_gateway);
+    pin_mut!(expired);
+    let (done, _, _) = future::select_all([
+        to_gateway as Pin<&mut dyn Future<Output = Result<(), io::Error>>>,
+        from_gateway,
+        expired,
+    ])
+    .await;
+    done.map(|()| (down.get(), up.get()))
+}
@@ -0,0 +1,223 @@
+//! Extracts the server name from a TLS ClientHello, without terminating TLS.
+
+use crate::config::HANDSHAKE_TIMEOUT;
+use std::convert::TryFrom;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+const CONTENT_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// The ClientHello may be split across several records, but is limited to a reasonable size.
+const MAX_CLIENT_HELLO_SIZE: usize = 64 * 1024;
+
+/// Reads the ClientHello from the start of a connection, returning the server name it contains, if any.
+/// Everything read is appended to `peeked`, so that it can be replayed afterwards.
+pub async fn read_server_name(
+    mut reader: impl AsyncRead + Unpin,
+    peeked: &mut Vec<u8>,
+) -> Result<Option<String>, io::Error> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        loop {
+            if let Some(client_hello) = client_hello(peeked)? {
+                return server_name(&client_hello);
+            }
+            if peeked.len() >= MAX_CLIENT_HELLO_SIZE {
+                return Err(invalid("ClientHello too large"));
+            }
+            if reader.read_buf(peeked).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    })
+    .await?
+}
+
+/// Reassembles the ClientHello message from the records in `buf`, or returns `None` if it's incomplete.
+fn client_hello(mut buf: &[u8]) -> Result<Option<Vec<u8>>, io::Error> {
+    let mut handshake = Vec::new();
+    loop {
+        if buf.len() < 5 {
+            return Ok(None);
+        }
+        let (header, rest) = buf.split_at(5);
+        if header[0] != CONTENT_HANDSHAKE {
+            return Err(invalid("Not a TLS handshake"));
+        }
+        let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        if rest.len() < len {
+            return Ok(None);
+        }
+        handshake.extend_from_slice(&rest[..len]);
+        buf = &rest[len..];
+
+        if let [kind, a, b, c, message @ ..] = &handshake[..] {
+            if *kind != HANDSHAKE_CLIENT_HELLO {
+                return Err(invalid("Not a TLS ClientHello"));
+            }
+            let len = usize::from(*a) << 16 | usize::from(*b) << 8 | usize::from(*c);
+            if message.len() >= len {
+                return Ok(Some(message[..len].to_vec()));
+            }
+        }
+    }
+}
+
+fn server_name(client_hello: &[u8]) -> Result<Option<String>, io::Error> {
+    let mut hello = Cursor(client_hello);
+    // legacy_version, random
+    hello.take(2 + 32)?;
+    // legacy_session_id
+    let len = hello.u8()?;
+    hello.take(usize::from(len))?;
+    // cipher_suites
+    let len = hello.u16()?;
+    hello.take(usize::from(len))?;
+    // legacy_compression_methods
+    let len = hello.u8()?;
+    hello.take(usize::from(len))?;
+    // extensions are optional in older versions
+    if hello.0.is_empty() {
+        return Ok(None);
+    }
+    let len = hello.u16()?;
+    let mut extensions = Cursor(hello.take(usize::from(len))?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()?;
+        let mut extension = Cursor(extensions.take(usize::from(len))?);
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let len = extension.u16()?;
+        let mut names = Cursor(extension.take(usize::from(len))?);
+        while !names.0.is_empty() {
+            let kind = names.u8()?;
+            let len = names.u16()?;
+            let name = names.take(usize::from(len))?;
+            if kind == NAME_TYPE_HOST_NAME {
+                let name =
+                    String::from_utf8(name.to_vec()).map_err(|_| invalid("Invalid server name"))?;
+                return Ok(Some(name));
+            }
+        }
+    }
+    Ok(None)
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        if self.0.len() < len {
+            return Err(invalid("Truncated ClientHello"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, io::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, io::Error> {
+        let bytes = <[u8; 2]>::try_from(self.take(2)?).expect("took 2 bytes");
+        Ok(u16::from_be_bytes(bytes))
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello_message(server_name: Option<&str>) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        // no session id, one cipher suite, no compression
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        let mut extensions = Vec::new();
+        // an extension before the server name, which is skipped
+        extensions.extend_from_slice(&[0, 10, 0, 4, 0, 2, 0, 29]);
+        if let Some(name) = server_name {
+            let len = name.len() as u16;
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(len + 5).to_be_bytes());
+            extensions.extend_from_slice(&(len + 3).to_be_bytes());
+            extensions.push(NAME_TYPE_HOST_NAME);
+            extensions.extend_from_slice(&len.to_be_bytes());
+            extensions.extend_from_slice(name.as_bytes());
+        }
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let len = body.len() as u32;
+        let mut message = vec![HANDSHAKE_CLIENT_HELLO];
+        message.extend_from_slice(&len.to_be_bytes()[1..]);
+        message.extend_from_slice(&body);
+        message
+    }
+
+    /// Splits a handshake message into records of at most `record_size` bytes.
+    fn records(message: &[u8], record_size: usize) -> Vec<u8> {
+        let mut records = Vec::new();
+        for chunk in message.chunks(record_size) {
+            records.extend_from_slice(&[CONTENT_HANDSHAKE, 3, 1]);
+            records.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            records.extend_from_slice(chunk);
+        }
+        records
+    }
+
+    #[test]
+    fn reassembles_client_hello() {
+        let message = client_hello_message(Some("example.com"));
+        for record_size in [message.len(), 7] {
+            let buf = records(&message, record_size);
+            let hello = client_hello(&buf).unwrap().unwrap();
+            assert_eq!(hello, message[4..]);
+            assert_eq!(server_name(&hello).unwrap().as_deref(), Some("example.com"));
+            // anything short of the whole message isn't enough
+            assert!(client_hello(&buf[..buf.len() - 1]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn without_server_name() {
+        let buf = records(&client_hello_message(None), 512);
+        let hello = client_hello(&buf).unwrap().unwrap();
+        assert_eq!(server_name(&hello).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_other_messages() {
+        assert!(client_hello(b"GET / HTTP/1.1\r\n").is_err());
+        let mut message = client_hello_message(Some("example.com"));
+        // ServerHello
+        message[0] = 2;
+        assert!(client_hello(&records(&message, 512)).is_err());
+        let message = client_hello_message(Some("example.com"));
+        assert!(server_name(&message[4..message.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_peeks_client_hello() {
+        let buf = records(&client_hello_message(Some("example.com")), 100);
+        let mut peeked = Vec::new();
+        let name = read_server_name(&buf[..], &mut peeked).await.unwrap();
+        assert_eq!(name.as_deref(), Some("example.com"));
+        assert_eq!(peeked, buf);
+
+        let e = read_server_name(&buf[..buf.len() - 1], &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,338 @@
+//! SOCKS5, as specified in RFC 1928, with username/password authentication from RFC 1929.
+
+use crate::config::HANDSHAKE_TIMEOUT;
+use crate::request::Target;
+use crate::tunnel::Credentials;
+use crate::wire;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+const VERSION: u8 = 5;
+
+const AUTH_NONE: u8 = 0;
+const AUTH_PASSWORD: u8 = 2;
+const AUTH_UNACCEPTABLE: u8 = 0xff;
+const AUTH_PASSWORD_VERSION: u8 = 1;
+
+const CMD_CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+pub const REP_SUCCEEDED: u8 = 0;
+pub const REP_GENERAL_FAILURE: u8 = 1;
+const REP_NOT_ALLOWED: u8 = 2;
+const REP_HOST_UNREACHABLE: u8 = 4;
+const REP_CONNECTION_REFUSED: u8 = 5;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Like `put_addr`, but sends a hostname as it is, for the proxy to resolve.
+fn put_target(buf: &mut Vec<u8>, target: &Target) -> Result<(), io::Error> {
+    match target.host.parse::<IpAddr>() {
+        Ok(ip) => put_addr(buf, SocketAddr::new(ip, target.port)),
+        Err(_) => {
+            buf.push(ATYP_DOMAIN);
+            wire::put_str(buf, &target.host)?;
+            buf.extend_from_slice(&target.port.to_be_bytes());
+        }
+    }
+    Ok(())
+}
+
+async fn read_addr(mut reader: impl AsyncRead + Unpin) -> Result<Target, io::Error> {
+    let host = match reader.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            reader.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            reader.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => wire::read_str(&mut reader).await?,
+        _ => return Err(invalid("Invalid SOCKS address type")),
+    };
+    let port = reader.read_u16().await?;
+    Ok(Target { host, port })
+}
+
+/// Reply code for a failed connection, so that SOCKS clients can tell why.
+pub fn reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => REP_NOT_ALLOWED,
+        io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        io::ErrorKind::NotFound | io::ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+/// Server side: reads a CONNECT request, without replying to it yet, see `reply`.
+/// Only clients which don't need to authenticate are accepted.
+pub async fn accept(mut stream: impl AsyncRead + AsyncWrite + Unpin) -> Result<Target, io::Error> {
+    let handshake = async {
+        let mut greeting = [0; 2];
+        stream.read_exact(&mut greeting).await?;
+        if greeting[0] != VERSION {
+            return Err(invalid("Not a SOCKS5 client"));
+        }
+        let mut methods = vec![0; usize::from(greeting[1])];
+        stream.read_exact(&mut methods).await?;
+        if !methods.contains(&AUTH_NONE) {
+            stream.write_all(&[VERSION, AUTH_UNACCEPTABLE]).await?;
+            stream.flush().await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS client requires authentication",
+            ));
+        }
+        stream.write_all(&[VERSION, AUTH_NONE]).await?;
+        stream.flush().await?;
+
+        let mut request = [0; 3];
+        stream.read_exact(&mut request).await?;
+        match request {
+            [VERSION, CMD_CONNECT, _] => read_addr(&mut stream).await,
+            [VERSION, _, _] => {
+                reply(&mut stream, REP_COMMAND_NOT_SUPPORTED).await?;
+                Err(invalid("Unsupported SOCKS command"))
+            }
+            _ => Err(invalid("Invalid SOCKS request")),
+        }
+    };
+    timeout(HANDSHAKE_TIMEOUT, handshake).await?
+}
+
+/// Server side: replies to a CONNECT request. The bound address isn't meaningful, since the connection is made remotely.
+pub async fn reply(mut stream: impl AsyncWrite + Unpin, code: u8) -> Result<(), io::Error> {
+    let mut reply = vec![VERSION, code, 0];
+    put_addr(&mut reply, (Ipv4Addr::UNSPECIFIED, 0).into());
+    stream.write_all(&reply).await?;
+    stream.flush().await
+}
+
+/// Client side: asks the proxy at the other end of `stream` to connect to `target`.
+pub async fn connect(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    target: &Target,
+    credentials: Option<&Credentials>,
+) -> Result<(), io::Error> {
+    let handshake = async {
+        let method = match credentials {
+            Some(_) => AUTH_PASSWORD,
+            None => AUTH_NONE,
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        stream.flush().await?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        match reply {
+            [VERSION, m] if m == method => {}
+            [VERSION, AUTH_UNACCEPTABLE] => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS proxy requires a different authentication method",
+                ))
+            }
+            _ => return Err(invalid("Invalid SOCKS method reply")),
+        }
+
+        if let Some(credentials) = credentials {
+            let mut request = vec![AUTH_PASSWORD_VERSION];
+            for field in [&credentials.username, &credentials.password] {
+                let len = u8::try_from(field.len()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "SOCKS credentials too long")
+                })?;
+                request.push(len);
+                request.extend_from_slice(field.as_bytes());
+            }
+            stream.write_all(&request).await?;
+            stream.flush().await?;
+
+            let mut reply = [0; 2];
+            stream.read_exact(&mut reply).await?;
+            match reply {
+                [AUTH_PASSWORD_VERSION, 0] => {}
+                [AUTH_PASSWORD_VERSION, _] => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "SOCKS proxy rejected credentials",
+                    ))
+                }
+                _ => return Err(invalid("Invalid SOCKS authentication reply")),
+            }
+        }
+
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        put_target(&mut request, target)?;
+        stream.write_all(&request).await?;
+        stream.flush().await?;
+
+        let mut reply = [0; 3];
+        stream.read_exact(&mut reply).await?;
+        match reply {
+            [VERSION, REP_SUCCEEDED, _] => {}
+            [VERSION, rep, _] => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("SOCKS proxy failed to connect: error {}", rep),
+                ))
+            }
+            _ => return Err(invalid("Invalid SOCKS reply")),
+        }
+        // bound address, which isn't needed
+        read_addr(&mut stream).await?;
+        Ok(())
+    };
+    timeout(HANDSHAKE_TIMEOUT, handshake).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn target(host: &str, port: u16) -> Target {
+        Target {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_and_accept() {
+        for (host, port) in [("example.com", 443), ("192.0.2.1", 80), ("2001:db8::1", 22)] {
+            let (mut client, mut server) = duplex(1024);
+            let server = async {
+                let target = accept(&mut server).await?;
+                reply(&mut server, REP_SUCCEEDED).await?;
+                Ok::<_, io::Error>(target)
+            };
+            let target = target(host, port);
+            let (connected, accepted) = tokio::join!(connect(&mut client, &target, None), server);
+            connected.unwrap();
+            let accepted = accepted.unwrap();
+            assert_eq!((accepted.host.as_str(), accepted.port), (host, port));
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_requires_no_authentication() {
+        let (mut client, mut server) = duplex(1024);
+        client
+            .write_all(&[VERSION, 1, AUTH_PASSWORD])
+            .await
+            .unwrap();
+        let e = accept(&mut server).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [VERSION, AUTH_UNACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn accept_rejects_other_commands() {
+        let (mut client, mut server) = duplex(1024);
+        // BIND
+        client
+            .write_all(&[VERSION, 1, AUTH_NONE, VERSION, 2, 0])
+            .await
+            .unwrap();
+        assert!(accept(&mut server).await.is_err());
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[2..4], [VERSION, REP_COMMAND_NOT_SUPPORTED]);
+    }
+
+    /// Connects with credentials to a proxy which answers the authentication request with `auth_reply`,
+    /// and the connection request with `reply_code`.
+    async fn connect_with_password(auth_reply: [u8; 2], reply_code: u8) -> Result<(), io::Error> {
+        let (client, mut proxy) = duplex(1024);
+        let proxy = async move {
+            let mut greeting = [0; 3];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [VERSION, 1, AUTH_PASSWORD]);
+            proxy.write_all(&[VERSION, AUTH_PASSWORD]).await.unwrap();
+            let mut auth = [0; 11];
+            proxy.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            proxy.write_all(&auth_reply).await.unwrap();
+            let mut request = [0; 3];
+            if proxy.read_exact(&mut request).await.is_err() {
+                return;
+            }
+            assert_eq!(request, [VERSION, CMD_CONNECT, 0]);
+            let target = read_addr(&mut proxy).await.unwrap();
+            assert_eq!((target.host.as_str(), target.port), ("example.com", 443));
+            let mut reply = vec![VERSION, reply_code, 0];
+            put_addr(&mut reply, (Ipv4Addr::UNSPECIFIED, 0).into());
+            proxy.write_all(&reply).await.unwrap();
+        };
+        let (target, credentials) = (target("example.com", 443), credentials());
+        let (connected, ()) = tokio::join!(connect(client, &target, Some(&credentials)), proxy);
+        connected
+    }
+
+    #[tokio::test]
+    async fn connect_authenticates() {
+        connect_with_password([AUTH_PASSWORD_VERSION, 0], REP_SUCCEEDED)
+            .await
+            .unwrap();
+        let e = connect_with_password([AUTH_PASSWORD_VERSION, 1], REP_SUCCEEDED)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        // the reply carries the version of the subnegotiation, not of SOCKS
+        let e = connect_with_password([VERSION, 0], REP_SUCCEEDED)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = connect_with_password([AUTH_PASSWORD_VERSION, 0], REP_CONNECTION_REFUSED)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn reply_codes() {
+        let code = |kind: io::ErrorKind| reply_code(&kind.into());
+        assert_eq!(code(io::ErrorKind::PermissionDenied), REP_NOT_ALLOWED);
+        assert_eq!(
+            code(io::ErrorKind::ConnectionRefused),
+            REP_CONNECTION_REFUSED
+        );
+        assert_eq!(code(io::ErrorKind::TimedOut), REP_HOST_UNREACHABLE);
+        assert_eq!(code(io::ErrorKind::Other), REP_GENERAL_FAILURE);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::config::HANDSHAKE_TIMEOUT;
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{
+    self, CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, RootCertStore,
+    ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::prelude::FromDer;
+
+/// SHA-256 fingerprint of a DER-encoded certificate.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    pub fn of(cert: &CertificateDer<'_>) -> Self {
+        Fingerprint(Sha256::digest(cert).into())
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected 32 hex bytes, optionally separated by colons",
+            )
+        };
+        // either 64 bare hex digits, or 32 pairs of them separated by colons
+        let pairs = match s.contains(':') {
+            true => s.split(':').collect::<Vec<_>>(),
+            false => (0..s.len())
+                .step_by(2)
+                .map(|i| s.get(i..i + 2).unwrap_or(""))
+                .collect(),
+        };
+        // `from_str_radix` alone would also accept a sign, like "+f"
+        let valid = |pair: &&str| pair.len() == 2 && pair.bytes().all(|b| b.is_ascii_hexdigit());
+        if pairs.len() != 32 || !pairs.iter().all(valid) {
+            return Err(invalid());
+        }
+        let mut fingerprint = [0; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(pairs) {
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Fingerprint(fingerprint))
+    }
+}
+
+/// How the client verifies the server's certificate.
+pub enum Verify {
+    Fingerprint(Fingerprint),
+    Ca(Vec<CertificateDer<'static>>),
+}
+
+/// Which client certificates the server accepts.
+/// Certificates must be issued by `ca` (if any) and have one of `fingerprints` (if any).
+pub struct Clients {
+    pub ca: Option<Vec<CertificateDer<'static>>>,
+    pub fingerprints: Vec<Fingerprint>,
+}
+
+/// A certificate chain and private key to present to the peer.
+pub struct Identity {
+    pub certs: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+pub struct Connector {
+    connector: TlsConnector,
+    name: ServerName<'static>,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn tls_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn root_store(certs: Vec<CertificateDer<'static>>) -> Result<RootCertStore, io::Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    match certs.len() {
+        0 => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", path.display()),
+        )),
+        _ => Ok(certs),
+    }
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No private key found in {}", path.display()),
+        )),
+    }
+}
+
+pub fn acceptor(identity: Identity, clients: Option<Clients>) -> Result<TlsAcceptor, io::Error> {
+    log::info!(
+        "Certificate fingerprint: {}",
+        Fingerprint::of(&identity.certs[0])
+    );
+    let provider = provider();
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+    let builder = match clients {
+        Some(Clients { ca, fingerprints }) => {
+            let ca = match ca {
+                Some(certs) => {
+                    let roots = Arc::new(root_store(certs)?);
+                    let verifier =
+                        WebPkiClientVerifier::builder_with_provider(roots, provider.clone())
+                            .build()
+                            .map_err(tls_error)?;
+                    Some(verifier)
+                }
+                None => None,
+            };
+            builder.with_client_cert_verifier(Arc::new(AllowedClients {
+                ca,
+                fingerprints,
+                provider,
+            }))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(identity.certs, identity.key)
+        .map_err(tls_error)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub fn connector(
+    verify: Verify,
+    name: ServerName<'static>,
+    identity: Option<Identity>,
+) -> Result<Connector, io::Error> {
+    let provider = provider();
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+    let builder =
+        match verify {
+            Verify::Fingerprint(fingerprint) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    fingerprint,
+                    provider,
+                })),
+            Verify::Ca(certs) => builder.with_root_certificates(root_store(certs)?),
+        };
+    let config = match identity {
+        Some(identity) => {
+            log::info!(
+                "Client certificate fingerprint: {}",
+                Fingerprint::of(&identity.certs[0])
+            );
+            builder
+                .with_client_auth_cert(identity.certs, identity.key)
+                .map_err(tls_error)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Connector {
+        connector: TlsConnector::from(Arc::new(config)),
+        name,
+    })
+}
+
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+) -> Result<server::TlsStream<TcpStream>, io::Error> {
+    timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await?
+}
+
+/// Subject of the certificate presented by a client, if any.
+pub fn client_subject(stream: &server::TlsStream<TcpStream>) -> Option<String> {
+    let (_, connection) = stream.get_ref();
+    let cert = connection.peer_certificates()?.first()?;
+    match X509Certificate::from_der(cert) {
+        Ok((_, cert)) => Some(cert.subject().to_string()),
+        Err(e) => {
+            log::warn!("Failed to parse client certificate: {}", e);
+            None
+        }
+    }
+}
+
+pub async fn connect(
+    connector: &Connector,
+    stream: TcpStream,
+) -> Result<client::TlsStream<TcpStream>, io::Error> {
+    let connect = connector.connector.connect(connector.name.clone(), stream);
+    timeout(HANDSHAKE_TIMEOUT, connect).await?
+}
+
+/// Accepts only a server certificate with a specific fingerprint, ignoring its name and issuer.
+#[derive(Debug)]
+struct PinnedVerifier {
+    fingerprint: Fingerprint,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = Fingerprint::of(end_entity);
+        if fingerprint == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            log::warn!("Unexpected certificate fingerprint: {}", fingerprint);
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Accepts client certificates which are issued by a trusted CA and/or have an allowed fingerprint.
+#[derive(Debug)]
+struct AllowedClients {
+    ca: Option<Arc<dyn ClientCertVerifier>>,
+    fingerprints: Vec<Fingerprint>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for AllowedClients {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        match &self.ca {
+            Some(ca) => ca.root_hint_subjects(),
+            None => &[],
+        }
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if let Some(ca) = &self.ca {
+            ca.verify_client_cert(end_entity, intermediates, now)?;
+        }
+        let fingerprint = Fingerprint::of(end_entity);
+        if self.fingerprints.is_empty() || self.fingerprints.contains(&fingerprint) {
+            Ok(ClientCertVerified::assertion())
+        } else {
+            log::warn!("Client certificate not allowed: {}", fingerprint);
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint() {
+        let hex = "00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff:".repeat(2);
+        let fingerprint = hex[..hex.len() - 1].parse::<Fingerprint>().unwrap();
+        assert_eq!(fingerprint.to_string(), hex[..hex.len() - 1]);
+        assert!("0011".repeat(16).parse::<Fingerprint>().is_ok());
+        assert!("+f".repeat(32).parse::<Fingerprint>().is_err());
+        assert!("0g".repeat(32).parse::<Fingerprint>().is_err());
+        assert!("00".repeat(31).parse::<Fingerprint>().is_err());
+        // colons only between pairs
+        let misplaced = format!("0:011:22{}", ":33".repeat(29));
+        assert!(misplaced.parse::<Fingerprint>().is_err());
+        assert!(format!("::{}", "00".repeat(32))
+            .parse::<Fingerprint>()
+            .is_err());
+        assert!(hex.as_str().parse::<Fingerprint>().is_err());
+        assert!("00112233"
+            .repeat(8)
+            .replacen("00", "00:", 1)
+            .parse::<Fingerprint>()
+            .is_err());
+    }
+}
@@ -0,0 +1,8 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A gateway connection, which may be a plain `TcpStream` or wrapped in another protocol.
+pub type Gateway = Box<dyn Transport>;
+
+pub trait Transport: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Transport for T {}
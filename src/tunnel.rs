@@ -0,0 +1,76 @@
+//! Connects to the gateway through an outbound HTTP or SOCKS5 proxy.
+
+use crate::config::HANDSHAKE_TIMEOUT;
+use crate::http;
+use crate::request::Target;
+use crate::socks;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Kind {
+    /// An HTTP proxy which supports the CONNECT method.
+    Http,
+    Socks5,
+}
+
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// An outbound proxy at `addr`, which is a `HOST:PORT` until it's given a `Resolver`.
+#[derive(Clone, Debug)]
+pub struct Proxy<A> {
+    pub kind: Kind,
+    pub addr: A,
+    pub credentials: Option<Credentials>,
+}
+
+/// Asks the proxy at the other end of `stream` to connect to `target`, after which `stream` is connected to it.
+/// A hostname is passed on as it is, for the proxy to resolve.
+pub async fn connect<A>(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    proxy: &Proxy<A>,
+    target: &Target,
+) -> Result<(), io::Error> {
+    match proxy.kind {
+        Kind::Http => connect_http(stream, target, proxy.credentials.as_ref()).await,
+        Kind::Socks5 => socks::connect(stream, target, proxy.credentials.as_ref()).await,
+    }
+}
+
+async fn connect_http(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    target: &Target,
+    credentials: Option<&Credentials>,
+) -> Result<(), io::Error> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(Credentials { username, password }) = credentials {
+        let basic = http::base64(format!("{}:{}", username, password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", basic));
+    }
+    request.push_str("\r\n");
+    timeout(HANDSHAKE_TIMEOUT, async {
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await
+    })
+    .await??;
+
+    // the response body would have to be drained otherwise, but a successful response has none
+    let head = http::read_head(&mut stream).await?;
+    let (status_line, _) = http::parse_head(&head)?;
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some("407") => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("HTTP proxy requires authentication: {}", status_line),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("HTTP proxy failed to connect: {}", status_line),
+        )),
+    }
+}
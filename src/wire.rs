@@ -0,0 +1,97 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Appends a string, prefixed by its length as a single byte.
+pub fn put_str(buf: &mut Vec<u8>, s: &str) -> Result<(), io::Error> {
+    let len = match u8::try_from(s.len()) {
+        Ok(len) => len,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "String too long to send",
+            ))
+        }
+    };
+    buf.push(len);
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+pub async fn read_str(mut reader: impl AsyncRead + Unpin) -> Result<String, io::Error> {
+    let len = reader.read_u8().await?;
+    let mut buf = vec![0; usize::from(len)];
+    reader.read_exact(&mut buf).await?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Appends an address, prefixed by its IP version.
+pub fn put_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+pub async fn read_addr(mut reader: impl AsyncRead + Unpin) -> Result<SocketAddr, io::Error> {
+    let ip = match reader.read_u8().await? {
+        4 => {
+            let mut octets = [0; 4];
+            reader.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        6 => {
+            let mut octets = [0; 16];
+            reader.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    let port = reader.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn strings() {
+        let mut buf = Vec::new();
+        put_str(&mut buf, "").unwrap();
+        put_str(&mut buf, "service").unwrap();
+        assert_eq!(buf, b"\x00\x07service");
+        let mut reader = &buf[..];
+        assert_eq!(read_str(&mut reader).await.unwrap(), "");
+        assert_eq!(read_str(&mut reader).await.unwrap(), "service");
+        assert!(reader.is_empty());
+
+        assert!(put_str(&mut buf, &"a".repeat(256)).is_err());
+        assert!(read_str(&b"\x02\xff\xfe"[..]).await.is_err());
+        assert!(read_str(&b"\x02a"[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn addrs() {
+        for addr in ["192.0.2.1:80", "[2001:db8::1]:443"] {
+            let addr = addr.parse().unwrap();
+            let mut buf = Vec::new();
+            put_addr(&mut buf, &addr);
+            let mut reader = &buf[..];
+            assert_eq!(read_addr(&mut reader).await.unwrap(), addr);
+            assert!(reader.is_empty());
+        }
+        let mut buf = Vec::new();
+        put_addr(&mut buf, &"192.0.2.1:80".parse().unwrap());
+        assert_eq!(buf, [4, 192, 0, 2, 1, 0, 80]);
+        assert!(read_addr(&b"\x05\x00"[..]).await.is_err());
+    }
+}
@@ -0,0 +1,605 @@
+//! Carries a gateway connection over WebSocket, for networks which only allow HTTP.
+//!
+//! After the HTTP Upgrade, everything written is sent as binary frames, and the payload of
+//! binary frames received is read back as a plain byte stream. Frames from the client are masked, as required.
+//! Pings are answered. Each side sends a close frame when it shuts down writing, which ends the other side's reads,
+//! so connections can be half-closed like plain TCP.
+
+use crate::http;
+use sha1::{Digest, Sha1};
+use std::convert::TryFrom;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+const MAX_CONTROL_SIZE: u64 = 125;
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Where the client sends its Upgrade request: `ws://HOST[:PORT][/PATH]`.
+/// The host is only used for the Host header, the connection is still made to the gateway address.
+#[derive(Clone, Debug)]
+pub struct Url {
+    host: String,
+    path: String,
+}
+
+impl FromStr for Url {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = match s.strip_prefix("ws://") {
+            Some(rest) => rest,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Expected ws://HOST[:PORT][/PATH]",
+                ))
+            }
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WebSocket URL has no host",
+            ));
+        }
+        Ok(Url {
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Which side of the connection this is. Only clients mask their frames.
+#[derive(Copy, Clone)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    http::base64(&sha1.finalize())
+}
+
+/// Client side: sends the Upgrade request, and checks that the server accepted it.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    url: &Url,
+) -> Result<WebSocket<S>, io::Error> {
+    let mut key = [0; 16];
+    getrandom::getrandom(&mut key)?;
+    let key = http::base64(&key);
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        url.path, url.host, key
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let head = http::read_head(&mut stream).await?;
+    let (status_line, headers) = http::parse_head(&head)?;
+    if !status_line.starts_with("HTTP/1.1 101 ") && status_line != "HTTP/1.1 101" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("WebSocket upgrade refused: {}", status_line),
+        ));
+    }
+    if !has_token(http::header(&headers, "upgrade"), "websocket")
+        || !has_token(http::header(&headers, "connection"), "upgrade")
+    {
+        return Err(invalid(
+            "WebSocket upgrade response lacks Upgrade or Connection",
+        ));
+    }
+    if http::header(&headers, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return Err(invalid("Invalid Sec-WebSocket-Accept"));
+    }
+    Ok(WebSocket::new(stream, Role::Client))
+}
+
+/// Whether a comma-separated header value contains `token`, ignoring case.
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// Server side: reads the Upgrade request, and accepts it if it's valid.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+) -> Result<WebSocket<S>, io::Error> {
+    let head = http::read_head(&mut stream).await?;
+    let (request_line, headers) = http::parse_head(&head)?;
+    // what RFC 6455 section 4.2.1 requires of the opening handshake
+    let upgrade = request_line.starts_with("GET ")
+        && request_line.ends_with(" HTTP/1.1")
+        && has_token(http::header(&headers, "upgrade"), "websocket")
+        && has_token(http::header(&headers, "connection"), "upgrade")
+        && http::header(&headers, "sec-websocket-version") == Some("13");
+    let key = match http::header(&headers, "sec-websocket-key") {
+        Some(key) if upgrade => key,
+        _ => {
+            http::write_response(&mut stream, http::Status::BadRequest).await?;
+            return Err(invalid("Not a WebSocket upgrade"));
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(WebSocket::new(stream, Role::Server))
+}
+
+pub struct WebSocket<S> {
+    inner: S,
+    role: Role,
+    read: Read,
+    /// Encoded frames which haven't been written to `inner` yet.
+    outgoing: Vec<u8>,
+    sent_close: bool,
+}
+
+enum Read {
+    /// Reading a frame header, which is complete once it reaches the length given by its first two bytes.
+    Header(Vec<u8>),
+    /// Reading the payload of a data frame straight into the caller's buffer.
+    Data {
+        remaining: u64,
+        mask: Option<[u8; 4]>,
+        offset: usize,
+    },
+    /// Reading the payload of a control frame, which is handled once complete.
+    Control {
+        opcode: u8,
+        len: usize,
+        mask: Option<[u8; 4]>,
+        payload: Vec<u8>,
+    },
+    Closed,
+}
+
+fn unmask(buf: &mut [u8], mask: Option<[u8; 4]>, offset: usize) {
+    if let Some(mask) = mask {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b ^= mask[(offset + i) % 4];
+        }
+    }
+}
+
+/// Total length of a header, based on its first two bytes.
+fn header_len(header: &[u8]) -> usize {
+    let len = match header[1] & 0x7f {
+        126 => 2 + 2,
+        127 => 2 + 8,
+        _ => 2,
+    };
+    match header[1] & MASKED {
+        0 => len,
+        _ => len + 4,
+    }
+}
+
+impl<S> WebSocket<S> {
+    fn new(inner: S, role: Role) -> Self {
+        Self {
+            inner,
+            role,
+            read: Read::Header(Vec::new()),
+            outgoing: Vec::new(),
+            sent_close: false,
+        }
+    }
+
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), io::Error> {
+        let frame = &mut self.outgoing;
+        frame.push(FIN | opcode);
+        let mask_bit = match self.role {
+            Role::Client => MASKED,
+            Role::Server => 0,
+        };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let start = frame.len();
+        match self.role {
+            Role::Client => {
+                let mut mask = [0; 4];
+                getrandom::getrandom(&mut mask)?;
+                frame.extend_from_slice(&mask);
+                frame.extend_from_slice(payload);
+                unmask(&mut frame[start + 4..], Some(mask), 0);
+            }
+            Role::Server => frame.extend_from_slice(payload),
+        }
+        Ok(())
+    }
+
+    /// Handles a complete frame header, returning the next read state.
+    fn read_header(&self, header: &[u8]) -> Result<Read, io::Error> {
+        let opcode = header[0] & 0x0f;
+        let (len, rest) = match header[1] & 0x7f {
+            126 => (
+                u64::from(u16::from_be_bytes([header[2], header[3]])),
+                &header[4..],
+            ),
+            127 => {
+                let bytes = <[u8; 8]>::try_from(&header[2..10]).expect("header has 8 length bytes");
+                (u64::from_be_bytes(bytes), &header[10..])
+            }
+            len => (u64::from(len), &header[2..]),
+        };
+        let mask = match header[1] & MASKED {
+            0 => None,
+            _ => Some(<[u8; 4]>::try_from(rest).expect("header has 4 mask bytes")),
+        };
+        match (self.role, mask) {
+            (Role::Server, None) => return Err(invalid("Unmasked frame from client")),
+            (Role::Client, Some(_)) => return Err(invalid("Masked frame from server")),
+            _ => {}
+        }
+        match opcode {
+            CONTINUATION | BINARY => Ok(Read::Data {
+                remaining: len,
+                mask,
+                offset: 0,
+            }),
+            TEXT => Err(invalid("Unexpected text frame")),
+            CLOSE | PING | PONG if len <= MAX_CONTROL_SIZE => Ok(Read::Control {
+                opcode,
+                len: len as usize,
+                mask,
+                payload: Vec::new(),
+            }),
+            _ => Err(invalid("Invalid frame")),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> WebSocket<S> {
+    fn poll_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        while !self.outgoing.is_empty() {
+            let written = match Pin::new(&mut self.inner).poll_write(cx, &self.outgoing) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => written,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            self.outgoing.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            match &mut this.read {
+                Read::Header(header) => {
+                    let needed = match header.len() {
+                        0 | 1 => 2,
+                        _ => header_len(header),
+                    };
+                    if header.len() == needed {
+                        let header = std::mem::take(header);
+                        this.read = this.read_header(&header)?;
+                        continue;
+                    }
+                    let mut bytes = [0; 14];
+                    let mut read = ReadBuf::new(&mut bytes[..needed - header.len()]);
+                    futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                    if read.filled().is_empty() {
+                        return match header.is_empty() {
+                            true => Poll::Ready(Ok(())),
+                            false => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                        };
+                    }
+                    header.extend_from_slice(read.filled());
+                }
+                Read::Data { remaining: 0, .. } => this.read = Read::Header(Vec::new()),
+                Read::Data {
+                    remaining,
+                    mask,
+                    offset,
+                } => {
+                    let len = usize::try_from(*remaining)
+                        .unwrap_or(usize::MAX)
+                        .min(buf.remaining());
+                    let mut read = ReadBuf::new(buf.initialize_unfilled_to(len));
+                    futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                    let read = read.filled_mut();
+                    if read.is_empty() && len > 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    unmask(read, *mask, *offset);
+                    let read = read.len();
+                    *remaining -= read as u64;
+                    *offset += read;
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Read::Control {
+                    opcode,
+                    len,
+                    mask,
+                    payload,
+                } if payload.len() == *len => {
+                    let (opcode, mask, mut payload) = (*opcode, *mask, std::mem::take(payload));
+                    unmask(&mut payload, mask, 0);
+                    this.read = match opcode {
+                        PING => {
+                            this.queue_frame(PONG, &payload)?;
+                            Read::Header(Vec::new())
+                        }
+                        // don't reply, since this side may still have data to send; it sends its own close on shutdown
+                        CLOSE => Read::Closed,
+                        _ => Read::Header(Vec::new()),
+                    };
+                    // send replies now if possible, otherwise they go out with the next write
+                    let _ = this.poll_outgoing(cx)?;
+                }
+                Read::Control { len, payload, .. } => {
+                    let mut bytes = [0; MAX_CONTROL_SIZE as usize];
+                    let mut read = ReadBuf::new(&mut bytes[..*len - payload.len()]);
+                    futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                    if read.filled().is_empty() {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    payload.extend_from_slice(read.filled());
+                }
+                Read::Closed => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = &mut *self;
+        futures::ready!(this.poll_outgoing(cx))?;
+        if this.sent_close {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = buf.len().min(MAX_FRAME_SIZE);
+        this.queue_frame(BINARY, &buf[..len])?;
+        // the frame is buffered, so it's written even if this is pending
+        let _ = this.poll_outgoing(cx)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        futures::ready!(self.poll_outgoing(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        if !self.sent_close {
+            self.queue_frame(CLOSE, &[])?;
+            self.sent_close = true;
+        }
+        futures::ready!(self.poll_outgoing(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    const UPGRADE: &str = "GET /chat HTTP/1.1\r\n\
+                           Host: server.example.com\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: keep-alive, Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                           Sec-WebSocket-Version: 13\r\n\r\n";
+
+    async fn handshake(request: &str) -> (Result<(), io::Error>, String) {
+        let (mut client, server) = duplex(4096);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let accepted = accept(server).await.map(drop);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        (accepted, String::from_utf8(response).unwrap())
+    }
+
+    #[test]
+    fn rfc_accept_key() {
+        // the example from RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_bytes() {
+        let (a, b) = duplex(4096);
+        let mut client = WebSocket::new(a, Role::Client);
+        let mut server = WebSocket::new(b, Role::Server);
+        // several frames, larger than the duplex buffer
+        let data = (0..40_000).map(|i| i as u8).collect::<Vec<_>>();
+        let send = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+        };
+        let mut received = Vec::new();
+        let (_, read) = tokio::join!(send, server.read_to_end(&mut received));
+        read.unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"reply").await.unwrap();
+        server.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"reply");
+    }
+
+    #[tokio::test]
+    async fn client_frames_are_masked() {
+        let (a, mut raw) = duplex(4096);
+        let mut client = WebSocket::new(a, Role::Client);
+        client.write_all(b"hello").await.unwrap();
+        let mut frame = [0; 2 + 4 + 5];
+        raw.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame[..2], [FIN | BINARY, MASKED | 5]);
+        let (mask, payload) = frame[2..].split_at_mut(4);
+        unmask(payload, Some(<[u8; 4]>::try_from(&*mask).unwrap()), 0);
+        assert_eq!(payload, b"hello");
+
+        let (a, mut raw) = duplex(4096);
+        let mut server = WebSocket::new(a, Role::Server);
+        server.write_all(b"hello").await.unwrap();
+        let mut frame = [0; 2 + 5];
+        raw.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, *b"\x82\x05hello");
+    }
+
+    #[tokio::test]
+    async fn reads_frames() {
+        let (a, mut raw) = duplex(4096);
+        let mut client = WebSocket::new(a, Role::Client);
+        // 16 and 64-bit lengths, a fragmented message, and a ping in between
+        raw.write_all(
+            b"\x02\x7e\x00\x02ab\x89\x04ping\x80\x7f\x00\x00\x00\x00\x00\x00\x00\x01c\x88\x00",
+        )
+        .await
+        .unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"abc");
+        let mut pong = [0; 2 + 4 + 4];
+        raw.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong[..2], [FIN | PONG, MASKED | 4]);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_frames() {
+        let frames: [(Role, &[u8]); 4] = [
+            (Role::Server, b"\x82\x01x"),
+            (Role::Client, b"\x82\x81\x00\x00\x00\x00x"),
+            (Role::Client, b"\x81\x01x"),
+            (Role::Client, b"\x89\x7e\x00\x7e"),
+        ];
+        for (role, frame) in frames {
+            let (a, mut raw) = duplex(4096);
+            let mut ws = WebSocket::new(a, role);
+            raw.write_all(frame).await.unwrap();
+            let e = ws.read(&mut [0; 16]).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", frame);
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_upgrade() {
+        let (accepted, response) = handshake(UPGRADE).await;
+        accepted.unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn accept_rejects_incomplete_upgrade() {
+        let requests = [
+            UPGRADE.replace("GET", "POST"),
+            UPGRADE.replace("Upgrade: websocket\r\n", "Upgrade: h2c\r\n"),
+            UPGRADE.replace("keep-alive, Upgrade", "keep-alive"),
+            UPGRADE.replace("Sec-WebSocket-Version: 13", "Sec-WebSocket-Version: 8"),
+            UPGRADE.replace("Sec-WebSocket-Version: 13\r\n", ""),
+            UPGRADE.replace("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n", ""),
+        ];
+        for request in &requests {
+            let (accepted, response) = handshake(request).await;
+            assert!(accepted.is_err(), "{}", request);
+            assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        }
+    }
+
+    /// Connects to a server which answers with `response`, after filling in the accept key.
+    async fn connect_to(response: &str) -> Result<(), io::Error> {
+        let (client, mut server) = duplex(4096);
+        let url = "ws://server.example.com/chat".parse::<Url>().unwrap();
+        let server = async {
+            let head = http::read_head(&mut server).await.unwrap();
+            let (_, headers) = http::parse_head(&head).unwrap();
+            let key = http::header(&headers, "sec-websocket-key").unwrap();
+            let response = response.replace("{accept}", &accept_key(key));
+            server.write_all(response.as_bytes()).await.unwrap();
+        };
+        let (connected, ()) = tokio::join!(connect(client, &url), server);
+        connected.map(drop)
+    }
+
+    #[tokio::test]
+    async fn connect_checks_response() {
+        let response = "HTTP/1.1 101 Switching Protocols\r\n\
+                        Upgrade: websocket\r\n\
+                        Connection: Upgrade\r\n\
+                        Sec-WebSocket-Accept: {accept}\r\n\r\n";
+        connect_to(response).await.unwrap();
+        let responses = [
+            response.replace("101 Switching Protocols", "200 OK"),
+            response.replace("Upgrade: websocket\r\n", ""),
+            response.replace("Connection: Upgrade\r\n", "Connection: keep-alive\r\n"),
+            response.replace("{accept}", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+        ];
+        for response in &responses {
+            assert!(connect_to(response).await.is_err(), "{}", response);
+        }
+    }
+}